@@ -2,7 +2,7 @@ use std::{
     collections::{BTreeMap, HashMap},
     fmt,
     path::{Path, PathBuf},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use crate::*;
@@ -31,6 +31,12 @@ type ToBeRemoved = bool;
 type GenerationsTagged = BTreeMap<Generation, ToBeRemoved>;
 type ProfilesTagged = HashMap<PathBuf, GenerationsTagged>;
 
+/// Age after which the out-link directory of a rebuild is considered left over
+const STALE_TEMPDIR_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How many directories deep the result links are looked for
+const RESULT_LINKS_DEPTH: usize = 5;
+
 impl NHRunnable for interface::CleanMode {
     fn run(&self) -> Result<()> {
         let mut profiles = Vec::new();
@@ -61,14 +67,16 @@ impl NHRunnable for interface::CleanMode {
                 let start_uid = 501;
                 let end_uid = start_uid + 100;
                 debug!("Scanning XDG profiles for users 0, ${start_uid}-${end_uid}");
+                let mut profile_dirs = Vec::new();
                 for user in unsafe { uzers::all_users() } {
                     if user.uid() >= start_uid && user.uid() < end_uid || user.uid() == 0 {
                         debug!(?user, "Adding XDG profiles for user");
-                        profiles.extend(profiles_in_dir(
-                            user.home_dir().join(".local/state/nix/profiles"),
-                        ));
+                        profile_dirs.push(user.home_dir().join(".local/state/nix/profiles"));
                     }
                 }
+                let profiles_per_user =
+                    util::parallel_map(&profile_dirs, jobs(args), |dir| profiles_in_dir(dir));
+                profiles.extend(profiles_per_user.concat());
                 args
             }
             interface::CleanMode::User(args) => {
@@ -77,22 +85,41 @@ impl NHRunnable for interface::CleanMode {
                 }
                 let user = nix::unistd::User::from_uid(uid)?.unwrap();
                 profiles.extend(profiles_in_dir(
-                    &PathBuf::from(std::env::var("HOME")?).join(".local/state/nix/profiles"),
+                    PathBuf::from(std::env::var("HOME")?).join(".local/state/nix/profiles"),
                 ));
                 profiles.extend(profiles_in_dir(
-                    &PathBuf::from("/nix/var/nix/profiles/per-user").join(user.name),
+                    PathBuf::from("/nix/var/nix/profiles/per-user").join(user.name),
                 ));
                 args
             }
         };
 
+        profiles.retain(|profile| {
+            let excluded = is_excluded(profile, &args.exclude);
+            if excluded {
+                debug!(?profile, "Excluded profile");
+            }
+            !excluded
+        });
+
         // Use mutation to raise errors as they come
         let mut profiles_tagged = ProfilesTagged::new();
-        for p in profiles {
-            profiles_tagged.insert(
-                p.clone(),
-                cleanable_generations(&p, args.keep, args.keep_since)?,
-            );
+        let generations = util::parallel_map(&profiles, jobs(args), |p| {
+            cleanable_generations(p, args.keep, args.keep_since, &args.keep_generation)
+        });
+        for (p, generations) in profiles.into_iter().zip(generations) {
+            profiles_tagged.insert(p, generations?);
+        }
+
+        for number in &args.keep_generation {
+            let exists = profiles_tagged
+                .values()
+                .any(|generations| generations.keys().any(|gen| gen.number == *number));
+            if !exists {
+                bail!(
+                    "Generation {number} passed to --keep-generation doesn't exist in any profile"
+                );
+            }
         }
 
         // Query gcroots
@@ -160,12 +187,50 @@ impl NHRunnable for interface::CleanMode {
             }
         }
 
+        if let Some(dirs) = &args.remove_result_links {
+            // After elevating, $HOME and the effective user are root's
+            let user = invoking_user(uid)?;
+            let dirs = match dirs.is_empty() {
+                true => vec![user.dir],
+                false => dirs.clone(),
+            };
+            for link in result_links(&dirs, user.uid) {
+                let age = link.symlink_metadata()?.modified()?;
+                let tbr = now
+                    .duration_since(age)
+                    .map_or(true, |val| val > args.keep_since.into());
+                gcroots_tagged.insert(link, tbr);
+            }
+        }
+
+        let tempdirs_tagged = match args.tmp {
+            true => {
+                let mut dirs = vec![std::env::temp_dir()];
+                if !dirs.iter().any(|dir| dir == Path::new("/tmp")) {
+                    dirs.push(PathBuf::from("/tmp"));
+                }
+                stale_tempdirs(&dirs, uid, now)
+            }
+            false => BTreeMap::new(),
+        };
+
         // Present the user the information about the paths to clean
         use owo_colors::OwoColorize;
         println!();
         println!("{}", "Welcome to nh clean".bold());
         println!("Keeping {} generation(s)", args.keep.green());
         println!("Keeping paths newer than {}", args.keep_since.green());
+        if !args.keep_generation.is_empty() {
+            let numbers: Vec<_> = args.keep_generation.iter().map(u32::to_string).collect();
+            println!("Keeping generation(s) {}", numbers.join(", ").green());
+        }
+        if !args.exclude.is_empty() {
+            let patterns: Vec<_> = args.exclude.iter().map(glob::Pattern::as_str).collect();
+            println!(
+                "Excluding the profiles matching {}",
+                patterns.join(", ").green()
+            );
+        }
         println!();
         println!("legend:");
         println!("{}: path to be kept", "OK".green());
@@ -190,6 +255,22 @@ impl NHRunnable for interface::CleanMode {
             }
             println!();
         }
+        if !tempdirs_tagged.is_empty() {
+            println!(
+                "{}",
+                "temporary directories (left over, and not in use)"
+                    .blue()
+                    .bold()
+            );
+            for (path, tbr) in &tempdirs_tagged {
+                if *tbr {
+                    println!("- {} {}", "DEL".red(), path.to_string_lossy());
+                } else {
+                    println!("- {} {}", "OK ".green(), path.to_string_lossy());
+                }
+            }
+            println!();
+        }
         for (profile, generations_tagged) in profiles_tagged.iter() {
             println!("{}", profile.to_string_lossy().blue().bold());
             for (gen, tbr) in generations_tagged.iter().rev() {
@@ -202,15 +283,72 @@ impl NHRunnable for interface::CleanMode {
             println!();
         }
 
+        // Estimate the space freed for the confirmation and the dry run
+        let freed_estimate = (args.ask || args.dry).then(|| {
+            let mut removed = Vec::new();
+            let mut kept = Vec::new();
+            let generations = profiles_tagged.values().flatten();
+            let paths = generations.map(|(gen, tbr)| (gen.path.clone(), tbr));
+            let gcroots = gcroots_tagged.iter().map(|(path, tbr)| (path.clone(), tbr));
+            let tempdirs = tempdirs_tagged
+                .iter()
+                .map(|(dir, tbr)| (dir.join("result"), tbr));
+            for (path, tbr) in paths.chain(gcroots).chain(tempdirs) {
+                // Generations and gcroots are symlinks, the store paths are what gets collected
+                if let Ok(store_path) = path.canonicalize() {
+                    if *tbr {
+                        removed.push(store_path);
+                    } else {
+                        kept.push(store_path);
+                    }
+                }
+            }
+            reclaimable_size(&removed, &kept)
+                .map_err(|err| warn!(?err, "Failed to estimate the space to be freed"))
+                .ok()
+        });
+
         // Clean the paths
         if args.ask {
-            info!("Confirm the cleanup plan?");
-            if !dialoguer::Confirm::new().default(false).interact()? {
-                return Ok(());
+            let generations =
+                count_removed(profiles_tagged.values().flat_map(|gens| gens.values()));
+            let profiles = profiles_tagged
+                .values()
+                .filter(|generations| generations.values().any(|tbr| *tbr))
+                .count();
+            println!(
+                "Removing {} generation(s) from {} profile(s), {} gcroot(s) and {} tempdir(s)",
+                generations.bold(),
+                profiles.bold(),
+                count_removed(gcroots_tagged.values()).bold(),
+                count_removed(tempdirs_tagged.values()).bold()
+            );
+            if let Some(Some(size)) = freed_estimate {
+                println!("This frees about {}", util::format_size(size).bold());
+            }
+
+            if !util::confirm("Confirm the cleanup plan?")? {
+                bail!(PhaseError::Declined);
             }
         }
 
-        if !args.dry {
+        let store = Path::new("/nix/store");
+        let free_before = util::free_space(store);
+
+        if args.dry {
+            if let Some(Some(size)) = freed_estimate {
+                println!("Would free about {}", util::format_size(size).bold());
+            }
+        } else {
+            for (dir, tbr) in &tempdirs_tagged {
+                if *tbr {
+                    info!("Removing {}", dir.to_string_lossy());
+                    if let Err(err) = std::fs::remove_dir_all(dir) {
+                        warn!(?dir, ?err, "Failed to remove directory");
+                    }
+                }
+            }
+
             for (path, tbr) in &gcroots_tagged {
                 if *tbr {
                     remove_path_nofail(path);
@@ -226,21 +364,97 @@ impl NHRunnable for interface::CleanMode {
             }
         }
 
-        commands::CommandBuilder::default()
-            .args(["nix", "store", "gc"])
-            .dry(args.dry)
-            .message("Performing garbage collection on the nix store")
-            .build()?
-            .exec()?;
+        if args.min_free.is_some() && free_before.is_err() {
+            warn!("Couldn't read the free space of the store, ignoring --min-free");
+        }
+        match gc_limit(
+            args.max_freed,
+            args.min_free,
+            free_before.as_ref().ok().copied(),
+        ) {
+            Some(0) => {
+                info!("The store already has enough free space, skipping the garbage collection")
+            }
+            limit => {
+                commands::CommandBuilder::default()
+                    .args(["nix", "store", "gc"])
+                    .args(
+                        limit
+                            .iter()
+                            .flat_map(|max| ["--max".to_owned(), max.to_string()]),
+                    )
+                    .dry(args.dry)
+                    .message("Performing garbage collection on the nix store")
+                    .build()?
+                    .exec()?;
+            }
+        }
+
+        if let (false, Ok(before), Ok(after)) = (args.dry, &free_before, util::free_space(store)) {
+            let cleaned_profiles = profiles_tagged
+                .values()
+                .filter(|generations| generations.values().any(|tbr| *tbr))
+                .count();
+            println!(
+                "Freed {} across {} profile(s)",
+                util::format_size(after.saturating_sub(*before)).bold(),
+                cleaned_profiles
+            );
+        }
+
+        if args.optimise {
+            let free_before = util::free_space(store);
+
+            commands::CommandBuilder::default()
+                .args(["nix", "store", "optimise"])
+                .dry(args.dry)
+                .message("Optimising the nix store")
+                .build()?
+                .exec()?;
+
+            if let (false, Ok(before), Ok(after)) = (args.dry, free_before, util::free_space(store))
+            {
+                let freed = util::format_size(after.saturating_sub(before));
+                println!("Optimising freed {}", freed.bold());
+            }
+        }
 
         Ok(())
     }
 }
 
+/// Bytes the garbage collection may free, at most --max-freed and what's missing to reach
+/// --min-free. None collects everything, and 0 nothing
+fn gc_limit(max_freed: Option<u64>, min_free: Option<u64>, free: Option<u64>) -> Option<u64> {
+    let missing = min_free
+        .zip(free)
+        .map(|(min, free)| min.saturating_sub(free));
+    [max_freed, missing].into_iter().flatten().min()
+}
+
+/// Number of paths tagged to be removed
+fn count_removed<'a>(tagged: impl IntoIterator<Item = &'a ToBeRemoved>) -> usize {
+    tagged.into_iter().filter(|tbr| **tbr).count()
+}
+
+/// Whether the path of the profile matches one of the --exclude globs
+fn is_excluded(profile: &Path, exclude: &[glob::Pattern]) -> bool {
+    let path = profile.to_string_lossy();
+    exclude.iter().any(|pattern| pattern.matches(&path))
+}
+
+fn jobs(args: &interface::CleanArgs) -> usize {
+    match args.jobs {
+        Some(jobs) => jobs as usize,
+        None => std::thread::available_parallelism().map_or(1, usize::from),
+    }
+}
+
 #[instrument(ret, level = "debug")]
 fn profiles_in_dir<P: AsRef<Path> + fmt::Debug>(dir: P) -> Vec<PathBuf> {
     let mut res = Vec::new();
     let dir = dir.as_ref();
+    let generation_regex = Regex::new(r"^(.*)-(\d+)-link$").unwrap();
 
     match dir.read_dir() {
         Ok(read_dir) => {
@@ -255,9 +469,7 @@ fn profiles_in_dir<P: AsRef<Path> + fmt::Debug>(dir: P) -> Vec<PathBuf> {
                                 .expect("Failed to get filename")
                                 .to_string_lossy();
 
-                            let generation_regex = Regex::new(r"^(.*)-(\d+)-link$").unwrap();
-
-                            if let Some(_) = generation_regex.captures(&name) {
+                            if generation_regex.is_match(&name) {
                                 res.push(path);
                             }
                         }
@@ -281,6 +493,7 @@ fn cleanable_generations(
     profile: &Path,
     keep: u32,
     keep_since: humantime::Duration,
+    keep_generations: &[u32],
 ) -> Result<GenerationsTagged> {
     let name = profile
         .file_name()
@@ -338,13 +551,246 @@ fn cleanable_generations(
         *tbr = false;
     }
 
+    for (gen, tbr) in result.iter_mut() {
+        if keep_generations.contains(&gen.number) {
+            *tbr = false;
+        }
+    }
+
     debug!("{:#?}", result);
     Ok(result)
 }
 
+/// User that ran nh, which is the one that ran sudo or doas when nh elevated itself
+fn invoking_user(uid: nix::unistd::Uid) -> Result<nix::unistd::User> {
+    let name = ["SUDO_USER", "DOAS_USER"]
+        .into_iter()
+        .find_map(|var| std::env::var(var).ok().filter(|name| !name.is_empty()));
+    let user = match name {
+        Some(name) if uid.is_root() => nix::unistd::User::from_name(&name)?,
+        _ => nix::unistd::User::from_uid(uid)?,
+    };
+    user.context("Couldn't find the user that ran nh")
+}
+
+/// Finds the result symlinks into the store under the directories, owned by the user unless it's
+/// root. The walk stops [RESULT_LINKS_DEPTH] directories deep and stays on the filesystem of
+/// each directory, so that mounts aren't scanned
+fn result_links(dirs: &[PathBuf], uid: nix::unistd::Uid) -> Vec<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut links = Vec::new();
+    let mut pending: Vec<(PathBuf, u64, usize)> = dirs
+        .iter()
+        .filter_map(|dir| Some((dir.clone(), dir.metadata().ok()?.dev(), 0)))
+        .collect();
+    while let Some((dir, dev, depth)) = pending.pop() {
+        let read_dir = match dir.read_dir() {
+            Ok(read_dir) => read_dir,
+            Err(error) => {
+                debug!(?dir, ?error, "Failed to read directory");
+                continue;
+            }
+        };
+
+        for entry in read_dir.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+
+            // DirEntry::metadata doesn't follow symlinks, so linked directories aren't walked
+            if metadata.is_dir() {
+                if depth < RESULT_LINKS_DEPTH && metadata.dev() == dev {
+                    pending.push((path, dev, depth + 1));
+                }
+                continue;
+            }
+
+            let is_result = entry.file_name().to_string_lossy().starts_with("result");
+            let owned = uid.is_root() || metadata.uid() == uid.as_raw();
+            if !metadata.is_symlink() || !is_result || !owned {
+                continue;
+            }
+
+            if let Ok(dst) = path.read_link() {
+                if dst.starts_with("/nix/store") {
+                    debug!(?path, ?dst, "Found result link");
+                    links.push(path);
+                }
+            }
+        }
+    }
+
+    links
+}
+
+/// Finds the out-link directories of the rebuilds in the temporary directories, owned by the user
+/// unless it's root. They are removed once left over, when old enough and not locked by a rebuild
+fn stale_tempdirs(
+    dirs: &[PathBuf],
+    uid: nix::unistd::Uid,
+    now: SystemTime,
+) -> BTreeMap<PathBuf, ToBeRemoved> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut tagged = BTreeMap::new();
+    for entry in dirs
+        .iter()
+        .filter_map(|dir| dir.read_dir().ok())
+        .flatten()
+        .flatten()
+    {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !util::TEMPDIR_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        {
+            continue;
+        }
+
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let owned = uid.is_root() || metadata.uid() == uid.as_raw();
+        if !metadata.is_dir() || !owned {
+            continue;
+        }
+
+        let old = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .is_some_and(|age| age > STALE_TEMPDIR_AGE);
+        let unused = matches!(util::try_lock_dir(&path, true), Ok(Some(_)));
+        debug!(?path, old, unused, "Found rebuild tempdir");
+        tagged.insert(path, old && unused);
+    }
+
+    tagged
+}
+
+/// Estimates the space freed by removing the paths, as the size of their closure that isn't part
+/// of the closure of a kept path. Other gcroots aren't accounted for, so this is an upper bound
+fn reclaimable_size(removed: &[PathBuf], kept: &[PathBuf]) -> Result<u64> {
+    let kept = closure_sizes(kept)?;
+    let size = closure_sizes(removed)?
+        .into_iter()
+        .filter(|(path, _)| !kept.contains_key(path))
+        .map(|(_, size)| size)
+        .sum();
+    Ok(size)
+}
+
+/// Size of every path in the closure of the given store paths
+fn closure_sizes(paths: &[PathBuf]) -> Result<HashMap<String, u64>> {
+    if paths.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let output = commands::CommandBuilder::default()
+        .args(["nix", "path-info", "--json", "--recursive"])
+        .args(paths)
+        .build()?
+        .exec_capture()?
+        .unwrap_or_default();
+    let json: serde_json::Value =
+        serde_json::from_str(&output).context("Parsing the output of nix path-info")?;
+
+    // Nix 2.19 switched from a list of objects to an object keyed by the store path
+    let infos: Vec<(String, &serde_json::Value)> = match &json {
+        serde_json::Value::Object(infos) => infos
+            .iter()
+            .map(|(path, info)| (path.clone(), info))
+            .collect(),
+        serde_json::Value::Array(infos) => infos
+            .iter()
+            .filter_map(|info| Some((info.get("path")?.as_str()?.to_owned(), info)))
+            .collect(),
+        _ => bail!("Unexpected output from nix path-info: {output}"),
+    };
+
+    Ok(infos
+        .into_iter()
+        .map(|(path, info)| {
+            let size = info.get("narSize").and_then(serde_json::Value::as_u64);
+            (path, size.unwrap_or_default())
+        })
+        .collect())
+}
+
 fn remove_path_nofail(path: &Path) {
     info!("Removing {}", path.to_string_lossy());
     if let Err(err) = std::fs::remove_file(path) {
         warn!(?path, ?err, "Failed to remove path");
     }
 }
+
+#[test]
+fn test_stale_tempdirs() {
+    let tmp = tempfile::tempdir().unwrap();
+    for name in ["nh-os-old", "nh-os-locked", "nh-home-new", "other"] {
+        std::fs::create_dir(tmp.path().join(name)).unwrap();
+    }
+    let _lock = util::try_lock_dir(&tmp.path().join("nh-os-locked"), false).unwrap();
+
+    // Looking from two hours ahead makes the directories old enough
+    let uid = nix::unistd::Uid::effective();
+    let later = SystemTime::now() + 2 * STALE_TEMPDIR_AGE;
+    let tagged = stale_tempdirs(&[tmp.path().to_owned()], uid, later);
+    assert_eq!(tagged.get(&tmp.path().join("nh-os-old")), Some(&true));
+    assert_eq!(tagged.get(&tmp.path().join("nh-os-locked")), Some(&false));
+    assert!(!tagged.contains_key(&tmp.path().join("other")));
+
+    let tagged = stale_tempdirs(&[tmp.path().to_owned()], uid, SystemTime::now());
+    assert_eq!(tagged.get(&tmp.path().join("nh-home-new")), Some(&false));
+}
+
+#[test]
+fn test_result_links() {
+    let home = tempfile::tempdir().unwrap();
+    let link = |dir: &Path| {
+        std::fs::create_dir_all(dir).unwrap();
+        std::os::unix::fs::symlink("/nix/store/abc-system", dir.join("result")).unwrap();
+        dir.join("result")
+    };
+    let shallow = link(&home.path().join("src/config"));
+    let deep =
+        (0..=RESULT_LINKS_DEPTH).fold(home.path().to_owned(), |dir, n| dir.join(n.to_string()));
+    link(&deep);
+    std::os::unix::fs::symlink("/tmp", home.path().join("result-other")).unwrap();
+
+    let uid = nix::unistd::Uid::effective();
+    assert_eq!(result_links(&[home.path().to_owned()], uid), [shallow]);
+}
+
+#[test]
+fn test_gc_limit() {
+    const GIB: u64 = 1 << 30;
+    assert_eq!(gc_limit(None, None, Some(GIB)), None);
+    assert_eq!(gc_limit(Some(10 * GIB), None, Some(GIB)), Some(10 * GIB));
+    assert_eq!(gc_limit(None, Some(10 * GIB), Some(4 * GIB)), Some(6 * GIB));
+    assert_eq!(gc_limit(None, Some(10 * GIB), Some(20 * GIB)), Some(0));
+    assert_eq!(
+        gc_limit(Some(2 * GIB), Some(10 * GIB), Some(4 * GIB)),
+        Some(2 * GIB)
+    );
+    // Without the free space, --min-free can't be honored
+    assert_eq!(gc_limit(None, Some(10 * GIB), None), None);
+}
+
+#[test]
+fn test_is_excluded() {
+    let exclude = [glob::Pattern::new("*/ci-*").unwrap()];
+    assert!(is_excluded(
+        Path::new("/nix/var/nix/profiles/per-user/root/ci-runner"),
+        &exclude
+    ));
+    assert!(!is_excluded(
+        Path::new("/nix/var/nix/profiles/system"),
+        &exclude
+    ));
+    assert!(!is_excluded(Path::new("/nix/var/nix/profiles/system"), &[]));
+}
@@ -1,15 +1,24 @@
 use color_eyre::{
-    eyre::{bail, Context},
-    Result,
+    eyre::{bail, eyre, Context, ContextCompat},
+    Report, Result,
 };
 
+use indicatif::{ProgressBar, ProgressStyle};
+use regex::Regex;
+use std::collections::VecDeque;
 use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 use thiserror::Error;
 
-use subprocess::{Exec, ExitStatus, Redirection};
-use tracing::{debug, info};
+use subprocess::{CaptureData, Exec, ExitStatus, Popen, Redirection};
+use tracing::{debug, info, warn};
 
-use crate::util::get_elevation_program;
+use crate::interface::{GlobalArgs, LogFormat};
+use crate::util::{get_elevation_program, no_color};
 
 #[derive(Debug, derive_builder::Builder)]
 #[builder(derive(Debug), setter(into))]
@@ -26,6 +35,198 @@ pub struct Command {
     /// Whether to run the command as root or not
     #[builder(default = "false")]
     root: bool,
+    /// Host to run the command on over SSH, instead of locally
+    #[builder(default)]
+    ssh: Option<String>,
+    /// Environment variables to set for the command
+    #[builder(setter(custom), default)]
+    env: Vec<(OsString, OsString)>,
+    /// Kill the command if it runs for longer than this, overriding --command-timeout
+    #[builder(setter(strip_option), default)]
+    timeout: Option<Duration>,
+    /// Whether the command may prompt the user, so it gets the terminal and never times out
+    #[builder(default = "false")]
+    interactive: bool,
+    /// Keep the end of stderr while streaming it, to show it in the error if the command fails
+    #[builder(default = "false")]
+    keep_stderr: bool,
+}
+
+/// Number of lines kept from the end of a failed command's output
+const TAIL_LINES: usize = 20;
+
+/// Flags of nix build that don't apply to nix eval
+const BUILD_FLAGS: &[&str] = &[
+    "--keep-going",
+    "-k",
+    "--keep-failed",
+    "-K",
+    "--rebuild",
+    "--no-link",
+];
+
+/// Same as [BUILD_FLAGS], for the flags followed by a value
+const BUILD_VALUE_FLAGS: &[&str] = &["--out-link", "-o", "--max-jobs", "-j", "--cores"];
+
+/// Matches the escape codes of colored terminal output
+const ANSI_ESCAPE: &str = r"\x1b\[[0-9;?]*[ -/]*[@-~]";
+
+/// Whether the output of the commands goes to stderr, set by [keep_stdout_clean]
+static STDOUT_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Whether Ctrl-C was pressed, so that no more commands are started
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Processes of the commands that are running, terminated on Ctrl-C
+static RUNNING: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Installs the Ctrl-C handler, which terminates the running commands so that their errors
+/// unwind nh normally and the temporary out-links are removed. Without a running command, like
+/// during the deletions of nh clean, and on a second Ctrl-C, nh exits right away
+pub fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| {
+        let running = RUNNING.lock().unwrap_or_else(|err| err.into_inner());
+        if INTERRUPTED.swap(true, Ordering::Relaxed) || running.is_empty() {
+            std::process::exit(130);
+        }
+        warn!("Interrupted, stopping the running commands");
+        for pid in running.iter() {
+            let pid = nix::unistd::Pid::from_raw(*pid as i32);
+            let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGTERM);
+        }
+    })
+    .wrap_err("Installing the Ctrl-C handler")
+}
+
+/// Whether Ctrl-C was pressed during the run
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Fails once Ctrl-C was pressed, before starting another command
+fn check_interrupted() -> Result<()> {
+    match interrupted() {
+        true => bail!(InterruptedError),
+        false => Ok(()),
+    }
+}
+
+/// Registers a started process to be terminated on Ctrl-C, until dropped
+struct Running(Option<u32>);
+
+impl Running {
+    fn new(process: &Popen) -> Self {
+        let pid = process.pid();
+        if let Some(pid) = pid {
+            RUNNING
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .push(pid);
+        }
+        Self(pid)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            let mut running = RUNNING.lock().unwrap_or_else(|err| err.into_inner());
+            running.retain(|running| *running != pid);
+        }
+    }
+}
+
+/// Sends the output of the commands to stderr, keeping stdout for a machine-readable result
+pub fn keep_stdout_clean() {
+    STDOUT_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+/// Where the commands write their output, which is stdout unless it's kept clean
+fn command_stdout() -> Redirection {
+    match STDOUT_TO_STDERR.load(Ordering::Relaxed) {
+        true => Redirection::Merge,
+        false => Redirection::None,
+    }
+}
+
+/// Writer for the output of the commands that nh relays itself
+fn output_writer() -> Box<dyn Write + Send> {
+    match STDOUT_TO_STDERR.load(Ordering::Relaxed) {
+        true => Box::new(std::io::stderr()),
+        false => Box::new(std::io::stdout()),
+    }
+}
+
+/// Program to run for `name`, where `NH_NIX_BIN` and `NH_GIT_BIN` override nix and git
+pub fn program(name: impl AsRef<OsStr>) -> OsString {
+    resolve_program(
+        name.as_ref(),
+        std::env::var_os("NH_NIX_BIN").filter(|bin| !bin.is_empty()),
+        std::env::var_os("NH_GIT_BIN").filter(|bin| !bin.is_empty()),
+    )
+}
+
+/// The other nix tools, like nix-store, are taken from the directory of the nix override when
+/// they exist there, so that they all come from the same install
+fn resolve_program(name: &OsStr, nix_bin: Option<OsString>, git_bin: Option<OsString>) -> OsString {
+    if name == "git" {
+        return git_bin.unwrap_or_else(|| name.to_owned());
+    }
+    let Some(nix_bin) = nix_bin else {
+        return name.to_owned();
+    };
+    if name == "nix" {
+        return nix_bin;
+    }
+    if name.to_string_lossy().starts_with("nix-") {
+        if let Some(dir) = Path::new(&nix_bin).parent() {
+            let sibling = dir.join(name);
+            if sibling.exists() {
+                return sibling.into_os_string();
+            }
+        }
+    }
+    name.to_owned()
+}
+
+/// Spinner shown while a command with a message runs without streaming its output, cleared
+/// when dropped
+struct Spinner(Option<ProgressBar>);
+
+impl Spinner {
+    fn new(message: &Option<String>) -> Self {
+        let args = GlobalArgs::get();
+        let show = message.is_some()
+            && !args.quiet
+            && args.log_format == LogFormat::Human
+            && std::io::stderr().is_terminal();
+        if !show {
+            return Self(None);
+        }
+
+        let bar = ProgressBar::new_spinner();
+        if let Ok(style) = ProgressStyle::with_template("{spinner} {elapsed}") {
+            bar.set_style(style);
+        }
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Self(Some(bar))
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.0 {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// Output of a command, with stdout and stderr captured separately
+#[derive(Debug)]
+pub struct CapturedOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: ExitStatus,
 }
 
 impl CommandBuilder {
@@ -39,38 +240,82 @@ impl CommandBuilder {
             .extend(input.into_iter().map(|s| s.as_ref().to_owned()));
         self
     }
+
+    pub fn env<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.env
+            .get_or_insert_with(Default::default)
+            .push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+        self
+    }
+
+    pub fn envs<K, V, I>(&mut self, input: I) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        for (key, value) in input {
+            self.env(key, value);
+        }
+        self
+    }
 }
 
 impl Command {
     pub fn exec(&self) -> Result<()> {
-        let (head, tail) = self.get_cmd_head_args()?;
-
-        let cmd = Exec::cmd(head)
-            .args(tail.as_ref())
-            .stderr(Redirection::None)
-            .stdout(Redirection::None);
+        let stderr = match self.keep_stderr {
+            true => Redirection::Pipe,
+            false => Redirection::None,
+        };
+        let cmd = self
+            .get_exec()?
+            .stdin(Redirection::None)
+            .stderr(stderr)
+            .stdout(command_stdout());
 
         if let Some(m) = &self.message {
             info!("{}", m);
         }
         debug!(?cmd);
 
+        if GlobalArgs::get().dry_run_commands {
+            let (head, tail) = self.get_cmd_head_args()?;
+            // Local unprivileged commands don't go through env_args, so show their variables too
+            let env = match self.root || self.ssh.is_some() {
+                true => vec![],
+                false => self.env_args(),
+            };
+            println!("{}", shell_quote(env.iter().chain([&head]).chain(&tail)));
+            return Ok(());
+        }
+
         if !self.dry {
+            let result = self
+                .join(cmd)
+                .and_then(|(status, tail)| match status.success() {
+                    true => Ok(()),
+                    false if interrupted() => bail!(InterruptedError),
+                    false => Err(with_tail(eyre!(ExitError(status)), &tail)),
+                });
             if let Some(m) = &self.message {
-                cmd.join().wrap_err(m.clone())?;
+                result.wrap_err(m.clone())?;
             } else {
-                cmd.join()?;
+                result?;
             }
         }
 
         Ok(())
     }
 
+    /// Captured commands are expected to only query the system, so they run even with
+    /// `--dry-run-commands`
     pub fn exec_capture(&self) -> Result<Option<String>> {
-        let (head, tail) = self.get_cmd_head_args()?;
-
-        let cmd = Exec::cmd(head)
-            .args(tail.as_ref())
+        let cmd = self
+            .get_exec()?
             .stderr(Redirection::None)
             .stdout(Redirection::Pipe);
 
@@ -80,28 +325,168 @@ impl Command {
         debug!(?cmd);
 
         if !self.dry {
-            Ok(Some(cmd.capture()?.stdout_str()))
+            let _spinner = Spinner::new(&self.message);
+            Ok(Some(self.capture(cmd)?.stdout_str()))
         } else {
             Ok(None)
         }
     }
 
+    /// Like [Command::exec_capture], but keeps stderr apart from stdout and returns the exit
+    /// status instead of ignoring it
+    pub fn exec_capture_split(&self) -> Result<Option<CapturedOutput>> {
+        let cmd = self
+            .get_exec()?
+            .stderr(Redirection::Pipe)
+            .stdout(Redirection::Pipe);
+
+        if let Some(m) = &self.message {
+            info!("{}", m);
+        }
+        debug!(?cmd);
+
+        if self.dry {
+            return Ok(None);
+        }
+
+        let _spinner = Spinner::new(&self.message);
+        let capture = self.capture(cmd)?;
+        Ok(Some(CapturedOutput {
+            stdout: capture.stdout_str(),
+            stderr: capture.stderr_str(),
+            status: capture.exit_status,
+        }))
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        if self.interactive {
+            return None;
+        }
+        self.timeout
+            .or_else(|| GlobalArgs::get().command_timeout.map(Into::into))
+    }
+
+    /// Waits for the command to finish, killing it once the timeout is exceeded, and returns the
+    /// end of stderr if it was kept
+    fn join(&self, cmd: Exec) -> Result<(ExitStatus, Vec<String>)> {
+        let timeout = self.timeout();
+        let mut process = cmd.popen()?;
+        let _running = Running::new(&process);
+        let tee = process.stderr.take().map(|stderr| {
+            std::thread::spawn(move || {
+                tee_tail(stderr, std::io::stderr(), |line| Some(line.to_owned()))
+            })
+        });
+
+        let status = match timeout {
+            None => process.wait()?,
+            Some(timeout) => match process.wait_timeout(timeout)? {
+                Some(status) => status,
+                None => {
+                    process.kill()?;
+                    process.wait()?;
+                    bail!(TimeoutError(timeout))
+                }
+            },
+        };
+
+        let tail = tee
+            .map(|tee| tee.join().unwrap_or_default())
+            .unwrap_or_default();
+        Ok((status, tail))
+    }
+
+    /// Captures the output of the command, killing it once the timeout is exceeded
+    fn capture(&self, cmd: Exec) -> Result<CaptureData> {
+        let timeout = self.timeout();
+        let mut process = cmd.popen()?;
+        let _running = Running::new(&process);
+        let mut communicator = process.communicate_start(None);
+        let result = match timeout {
+            Some(timeout) => communicator.limit_time(timeout).read(),
+            None => communicator.read(),
+        };
+        match result {
+            Ok((stdout, stderr)) => Ok(CaptureData {
+                stdout: stdout.unwrap_or_default(),
+                stderr: stderr.unwrap_or_default(),
+                exit_status: process.wait()?,
+            }),
+            Err(err) if err.error.kind() == std::io::ErrorKind::TimedOut => {
+                process.kill()?;
+                process.wait()?;
+                bail!(TimeoutError(timeout.unwrap_or_default()))
+            }
+            Err(err) => Err(err.error.into()),
+        }
+    }
+
+    fn get_exec(&self) -> Result<Exec> {
+        check_interrupted()?;
+        let (head, tail) = self.get_cmd_head_args()?;
+        let mut cmd = Exec::cmd(head).args(tail.as_ref());
+
+        // Local unprivileged commands get the variables directly, the rest go through env_args
+        if !self.root && self.ssh.is_none() {
+            for (key, value) in &self.env {
+                cmd = cmd.env(key, value);
+            }
+        }
+
+        Ok(cmd)
+    }
+
+    /// Arguments to set the environment variables through `env`, for commands that don't inherit
+    /// nh's environment, like the ones going through sudo or ssh
+    fn env_args(&self) -> Vec<OsString> {
+        if self.env.is_empty() {
+            return vec![];
+        }
+
+        let mut args = vec![OsString::from("env")];
+        for (key, value) in &self.env {
+            let mut assignment = key.clone();
+            assignment.push("=");
+            assignment.push(value);
+            args.push(assignment);
+        }
+        args
+    }
+
     fn get_cmd_head_args(&self) -> Result<(OsString, Vec<OsString>)> {
+        if let Some(host) = &self.ssh {
+            let mut tail = Vec::new();
+            if self.root || self.interactive {
+                // Allocate a tty so that sudo or the command can prompt
+                tail.push(OsString::from("-t"));
+            }
+            tail.extend([OsString::from(host), OsString::from("--")]);
+            if self.root {
+                tail.push(OsString::from("sudo"));
+            }
+            tail.extend(self.env_args());
+            tail.extend(self.args.iter().cloned());
+            return Ok((OsString::from("ssh"), tail));
+        }
+
+        if self.args.is_empty() {
+            bail!("Args was length 0");
+        }
+        let head = program(&self.args[0]);
+        let tail = self.args[1..].to_vec();
+
         if self.root {
-            let (program, additional_args) = get_elevation_program()?;
-            let concatenated = [&additional_args[..], &self.args[..]].concat();
-            Ok((program, concatenated))
+            let (elevation, additional_args) = get_elevation_program()?;
+            let concatenated = [
+                &additional_args[..],
+                &self.env_args()[..],
+                &[head],
+                &tail[..],
+            ]
+            .concat();
+            Ok((elevation, concatenated))
         } else {
-            if self.args.len() == 0 {
-                bail!("Args was length 0");
-            }
-            let head = self.args[0].clone();
-            let tail = if self.args.len() > 1 {
-                self.args[1..].to_vec()
-            } else {
-                vec![]
-            };
-            Ok((head.clone(), tail))
+            Ok((head, tail))
         }
     }
 }
@@ -118,6 +503,18 @@ pub struct BuildCommand {
     extra_args: Vec<OsString>,
     /// Use nom for the nix build
     nom: bool,
+    /// Host to build on over SSH, instead of locally
+    #[builder(default)]
+    build_host: Option<String>,
+    /// Nix file to build the flakeref from as an attribute path, instead of a flake
+    #[builder(default)]
+    file: Option<String>,
+    /// Number of times to retry a failed build
+    #[builder(default)]
+    retries: u32,
+    /// System to build for, like aarch64-linux, instead of the one of this machine
+    #[builder(default)]
+    system: Option<String>,
 }
 
 impl BuildCommandBuilder {
@@ -137,43 +534,475 @@ impl BuildCommand {
     pub fn exec(&self) -> Result<()> {
         info!("{}", self.message);
 
-        let exit = if self.nom {
-            let cmd = {
-                Exec::cmd("nix")
-                    .args(&[
-                        "build",
-                        &self.flakeref,
-                        "--log-format",
-                        "internal-json",
-                        "--verbose",
-                    ])
-                    .args(&self.extra_args)
-                    .stdout(Redirection::Pipe)
-                    .stderr(Redirection::Merge)
-                    | Exec::cmd("nom").args(&["--json"])
+        if GlobalArgs::get().dry_run_commands {
+            if let Some(host) = &self.build_host {
+                info!("Would build on {host}");
             }
-            .stdout(Redirection::None);
-            debug!(?cmd);
-            cmd.join()
-        } else {
-            let cmd = Exec::cmd("nix")
-                .args(&["build", &self.flakeref])
-                .args(&self.extra_args)
-                .stdout(Redirection::None)
+            let installable = self.installable(&self.flakeref);
+            let nix = program("nix");
+            let nix_args = self.nix_args();
+            let args = [nix.as_os_str(), OsStr::new("build")].into_iter();
+            let args = args
+                .chain(installable.iter().map(OsStr::new))
+                .chain(nix_args.iter().map(OsString::as_os_str));
+            println!("{}", shell_quote(args));
+            return Ok(());
+        }
+
+        // When building remotely, the local build only realises the copied store path
+        let installable = match &self.build_host {
+            Some(host) => vec![self.build_remote(host)?],
+            None => self.installable(&self.flakeref),
+        };
+
+        self.retrying(|| {
+            let (status, tail) = self
+                .run_build(&installable)
+                .wrap_err(self.message.clone())?;
+            match status {
+                ExitStatus::Exited(0) => Ok(()),
+                other => Err(with_tail(eyre!(ExitError(other)), &tail)),
+            }
+        })
+    }
+
+    /// Runs the build step until it succeeds or the retries are used up, backing off between
+    /// the attempts
+    fn retrying<T>(&self, mut step: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            check_interrupted()?;
+            match step() {
+                Ok(value) => return Ok(value),
+                Err(_) if interrupted() => bail!(InterruptedError),
+                Err(err) if attempt < self.retries => {
+                    attempt += 1;
+                    let backoff = Duration::from_secs(2u64.pow(attempt));
+                    warn!(
+                        "Build failed: {}, retrying in {}s (attempt {attempt}/{})",
+                        err.root_cause(),
+                        backoff.as_secs(),
+                        self.retries
+                    );
+                    std::thread::sleep(backoff);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Runs the build, returning the end of its errors to show if it fails
+    fn run_build(&self, installable: &[String]) -> subprocess::Result<(ExitStatus, Vec<String>)> {
+        if self.nom {
+            let nix = Exec::cmd(program("nix"))
+                .arg("build")
+                .args(installable)
+                .args(&["--log-format", "internal-json", "--verbose"])
+                .args(&self.nix_args())
+                .stdout(Redirection::Pipe)
                 .stderr(Redirection::Merge);
+            let nom = Exec::cmd("nom").args(&["--json"]).stdin(Redirection::Pipe);
+            debug!(?nix, ?nom);
+
+            Self::run_nom(nix, nom)
+        } else {
+            // Only stderr is relayed, to keep the end of the errors, stdout goes straight through
+            let cmd = Exec::cmd(program("nix"))
+                .arg("build")
+                .args(installable)
+                .args(&self.nix_args())
+                .stdout(command_stdout())
+                .stderr(Redirection::Pipe);
 
             debug!(?cmd);
-            cmd.join()
+            let mut process = cmd.popen()?;
+            let _running = Running::new(&process);
+            let tail = match process.stderr.take() {
+                Some(stderr) => tee_tail(stderr, std::io::stderr(), |line| Some(line.to_owned())),
+                None => Vec::new(),
+            };
+            Ok((process.wait()?, tail))
+        }
+    }
+
+    /// Runs the build through nom, stripping its colors if needed. The logs of nix are passed on
+    /// to nom, keeping its error messages along the way
+    fn run_nom(nix: Exec, nom: Exec) -> subprocess::Result<(ExitStatus, Vec<String>)> {
+        let strip_color = no_color();
+        let nom_stdout = match strip_color {
+            true => Redirection::Pipe,
+            false => command_stdout(),
         };
-        match exit.wrap_err(self.message.clone())? {
-            ExitStatus::Exited(0) => (),
-            other => bail!(ExitError(other)),
+
+        // nom goes first, so that nix isn't left running if nom can't be started
+        let mut nom = nom.stdout(nom_stdout).popen()?;
+        let _nom_running = Running::new(&nom);
+        let mut nix = nix.popen()?;
+        let _nix_running = Running::new(&nix);
+
+        let logs = nix.stdout.take().unwrap();
+        let nom_stdin = nom.stdin.take().unwrap();
+        let tee = std::thread::spawn(move || tee_tail(logs, nom_stdin, nix_error));
+
+        // nom doesn't know about NO_COLOR, so strip its escape codes on the way through
+        if let Some(output) = nom.stdout.take() {
+            let ansi = Regex::new(ANSI_ESCAPE).unwrap();
+            let mut writer = output_writer();
+            for line in BufReader::new(output).lines() {
+                writeln!(writer, "{}", ansi.replace_all(&line?, ""))?;
+            }
         }
 
-        Ok(())
+        let status = nix.wait()?;
+        let tail = tee.join().unwrap_or_default();
+        nom.wait()?;
+        Ok((status, tail))
     }
+
+    /// Arguments of nix build besides the installable
+    fn nix_args(&self) -> Vec<OsString> {
+        let mut args = self.extra_args.clone();
+        if let Some(system) = &self.system {
+            args.extend([OsString::from("--system"), OsString::from(system)]);
+        }
+        args
+    }
+
+    /// Arguments of nix build that also apply to evaluating the derivation
+    fn eval_args(&self) -> Vec<OsString> {
+        eval_args(self.nix_args())
+    }
+
+    /// Arguments selecting the attribute to build, either from the flake or the file
+    fn installable(&self, attr: &str) -> Vec<String> {
+        match &self.file {
+            Some(file) => vec!["--file".to_owned(), file.to_owned(), attr.to_owned()],
+            None => vec![attr.to_owned()],
+        }
+    }
+
+    /// Builds the derivation on the build host and copies the result back, returning its store path
+    fn build_remote(&self, host: &str) -> Result<String> {
+        let store = format!("ssh://{host}");
+
+        let drv = CommandBuilder::default()
+            .args(["nix", "eval", "--raw"])
+            .args(self.installable(&format!("{}.drvPath", self.flakeref)))
+            .args(self.eval_args())
+            .message("Evaluating the derivation")
+            .build()?
+            .exec_capture()?
+            .unwrap_or_default();
+        if drv.is_empty() {
+            bail!(
+                "Failed to evaluate the derivation path of {}",
+                self.flakeref
+            );
+        }
+
+        CommandBuilder::default()
+            .args(["nix", "copy", "--derivation", "--to", &store, &drv])
+            .message(format!("Copying the derivation to {host}"))
+            .build()?
+            .exec()?;
+
+        // Built without a message, which would put a spinner in front of the ssh prompts. The
+        // logs of the build are shown if it fails
+        info!("Building on {host}");
+        let out_path = self.retrying(|| {
+            let Some(output) = CommandBuilder::default()
+                .args(["ssh", host, "--", "nix-store", "--realise", &drv])
+                .build()?
+                .exec_capture_split()?
+            else {
+                bail!("Building {drv} on {host} produced no output");
+            };
+            if !output.status.success() {
+                let lines: Vec<_> = output.stderr.lines().map(str::to_owned).collect();
+                let tail = &lines[lines.len().saturating_sub(TAIL_LINES)..];
+                bail!(with_tail(eyre!(ExitError(output.status)), tail)
+                    .wrap_err(format!("Failed to build {drv} on {host}")));
+            }
+            output
+                .stdout
+                .lines()
+                .last()
+                .map(str::to_owned)
+                .with_context(|| format!("Building {drv} on {host} produced no output"))
+        })?;
+
+        CommandBuilder::default()
+            .args(["nix", "copy", "--from", &store, &out_path])
+            .message(format!("Copying the result from {host}"))
+            .build()?
+            .exec()?;
+
+        Ok(out_path)
+    }
+}
+
+/// Arguments of nix build that also apply to nix eval, which are all of them but the ones only
+/// nix build knows about
+pub fn eval_args<S: AsRef<OsStr>>(build_args: impl IntoIterator<Item = S>) -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut build_args = build_args.into_iter();
+    while let Some(arg) = build_args.next() {
+        let arg = arg.as_ref();
+        let arg_str = arg.to_string_lossy();
+        if BUILD_VALUE_FLAGS.contains(&arg_str.as_ref()) {
+            build_args.next();
+            continue;
+        }
+        if BUILD_FLAGS.contains(&arg_str.as_ref()) {
+            continue;
+        }
+        args.push(arg.to_owned());
+    }
+    args
+}
+
+/// Forwards the lines to the writer, and returns the last lines that `keep` picks from them,
+/// without their escape codes
+fn tee_tail(
+    reader: impl Read,
+    mut writer: impl Write,
+    keep: impl Fn(&str) -> Option<String>,
+) -> Vec<String> {
+    let ansi = Regex::new(ANSI_ESCAPE).unwrap();
+    let mut reader = BufReader::new(reader);
+    let mut tail = VecDeque::with_capacity(TAIL_LINES);
+    let mut buf = Vec::new();
+
+    while reader.read_until(b'\n', &mut buf).unwrap_or(0) > 0 {
+        // Keep reading even if the writer is gone, so that the command doesn't block on its output
+        let _ = writer.write_all(&buf).and_then(|_| writer.flush());
+
+        let line = String::from_utf8_lossy(&buf);
+        if let Some(kept) = keep(line.trim_end_matches('\n')) {
+            for kept_line in ansi.replace_all(&kept, "").lines() {
+                if tail.len() == TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(kept_line.to_owned());
+            }
+        }
+        buf.clear();
+    }
+
+    tail.into()
+}
+
+/// Error message of a line of the internal-json log format of nix
+fn nix_error(line: &str) -> Option<String> {
+    let json = line.strip_prefix("@nix ")?;
+    let entry: serde_json::Value = serde_json::from_str(json).ok()?;
+    if entry["action"] != "msg" || entry["level"] != 0 {
+        return None;
+    }
+    entry["msg"].as_str().map(str::to_owned)
+}
+
+/// Adds the end of the command output to the error, so that it shows what actually failed
+fn with_tail(err: Report, tail: &[String]) -> Report {
+    match tail.is_empty() {
+        true => err,
+        false => err.wrap_err(tail.join("\n")),
+    }
+}
+
+/// Joins the arguments into a command line that can be pasted into a shell
+fn shell_quote<S: AsRef<OsStr>>(args: impl IntoIterator<Item = S>) -> String {
+    args.into_iter()
+        .map(|arg| {
+            let arg = arg.as_ref().to_string_lossy();
+            let is_plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./:=@+,%#~".contains(c));
+            if is_plain {
+                arg.into_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 #[derive(Debug, Error)]
 #[error("Command exited with status {0:?}")]
 pub struct ExitError(ExitStatus);
+
+#[derive(Debug, Error)]
+#[error("Interrupted")]
+pub struct InterruptedError;
+
+#[derive(Debug, Error)]
+#[error("Command timed out after {}", humantime::format_duration(*.0))]
+pub struct TimeoutError(Duration);
+
+#[test]
+fn test_command_env() {
+    let output = CommandBuilder::default()
+        .args(["sh", "-c", "echo $NH_TEST_A $NH_TEST_B"])
+        .env("NH_TEST_A", "a")
+        .envs([("NH_TEST_B", "b")])
+        .build()
+        .unwrap()
+        .exec_capture()
+        .unwrap();
+    assert_eq!(output.as_deref(), Some("a b\n"));
+}
+
+#[test]
+fn test_command_env_remote() {
+    let (head, tail) = CommandBuilder::default()
+        .args(["switch-to-configuration", "boot"])
+        .env("NIXOS_INSTALL_BOOTLOADER", "1")
+        .ssh(Some("host".to_owned()))
+        .root(true)
+        .build()
+        .unwrap()
+        .get_cmd_head_args()
+        .unwrap();
+    assert_eq!(head, "ssh");
+    assert_eq!(
+        tail,
+        [
+            "-t",
+            "host",
+            "--",
+            "sudo",
+            "env",
+            "NIXOS_INSTALL_BOOTLOADER=1",
+            "switch-to-configuration",
+            "boot"
+        ]
+    );
+}
+
+#[test]
+fn test_command_interactive() {
+    let command = CommandBuilder::default()
+        .args(["git", "pull"])
+        .ssh(Some("host".to_owned()))
+        .timeout(Duration::from_secs(1))
+        .interactive(true)
+        .build()
+        .unwrap();
+    assert_eq!(command.timeout(), None);
+    let (head, tail) = command.get_cmd_head_args().unwrap();
+    assert_eq!(head, "ssh");
+    assert_eq!(tail, ["-t", "host", "--", "git", "pull"]);
+}
+
+#[test]
+fn test_command_capture_split() {
+    let output = CommandBuilder::default()
+        .args(["sh", "-c", "echo out; echo err >&2; exit 3"])
+        .build()
+        .unwrap()
+        .exec_capture_split()
+        .unwrap()
+        .unwrap();
+    assert_eq!(output.stdout, "out\n");
+    assert_eq!(output.stderr, "err\n");
+    assert_eq!(output.status, ExitStatus::Exited(3));
+}
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!(
+        shell_quote(["nix", "build", ".#foo", "it's here", ""]),
+        r"nix build .#foo 'it'\''s here' ''"
+    );
+}
+
+#[test]
+fn test_command_timeout() {
+    let err = CommandBuilder::default()
+        .args(["sleep", "10"])
+        .timeout(Duration::from_millis(100))
+        .build()
+        .unwrap()
+        .exec_capture()
+        .unwrap_err();
+    assert!(err.is::<TimeoutError>());
+}
+
+#[test]
+fn test_resolve_program() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("nix-store"), "").unwrap();
+    let nix_bin = dir.path().join("nix").into_os_string();
+
+    let resolve = |name: &str, nix_bin: Option<&OsString>| {
+        resolve_program(
+            OsStr::new(name),
+            nix_bin.cloned(),
+            Some(OsString::from("/opt/git")),
+        )
+    };
+    assert_eq!(resolve("nix", None), "nix");
+    assert_eq!(resolve("nix-store", None), "nix-store");
+    assert_eq!(resolve("nix", Some(&nix_bin)), nix_bin);
+    assert_eq!(
+        resolve("nix-store", Some(&nix_bin)),
+        dir.path().join("nix-store").into_os_string()
+    );
+    assert_eq!(resolve("nix-env", Some(&nix_bin)), "nix-env");
+    assert_eq!(resolve("git", Some(&nix_bin)), "/opt/git");
+    assert_eq!(resolve("ssh", Some(&nix_bin)), "ssh");
+}
+
+#[test]
+fn test_tee_tail() {
+    let input: String = (1..=30).map(|n| format!("line {n}\n")).collect();
+    let mut output = Vec::new();
+    let tail = tee_tail(input.as_bytes(), &mut output, |line| Some(line.to_owned()));
+    assert_eq!(output, input.as_bytes());
+    assert_eq!(tail.len(), TAIL_LINES);
+    assert_eq!(tail.first().unwrap(), "line 11");
+    assert_eq!(tail.last().unwrap(), "line 30");
+
+    let colored = "\x1b[31;1merror:\x1b[0m attribute 'foo' missing\n";
+    let tail = tee_tail(colored.as_bytes(), std::io::sink(), |line| {
+        Some(line.to_owned())
+    });
+    assert_eq!(tail, ["error: attribute 'foo' missing"]);
+}
+
+#[test]
+fn test_nix_error() {
+    let error = r#"@nix {"action":"msg","level":0,"msg":"error: attribute 'foo' missing"}"#;
+    assert_eq!(nix_error(error).unwrap(), "error: attribute 'foo' missing");
+    let warning = r#"@nix {"action":"msg","level":1,"msg":"warning: Git tree is dirty"}"#;
+    assert_eq!(nix_error(warning), None);
+    assert_eq!(nix_error(r#"@nix {"action":"start","id":1}"#), None);
+    assert_eq!(nix_error("plain output"), None);
+}
+
+#[test]
+fn test_build_eval_args() {
+    let build = BuildCommandBuilder::default()
+        .message("Building")
+        .flakeref(".#foo")
+        .extra_args(["--out-link", "/tmp/result", "--impure", "--keep-going"])
+        .extra_args(["--max-jobs", "4", "--cores", "2"])
+        .extra_args(["--override-input", "nixpkgs", "path:/nixpkgs"])
+        .nom(false)
+        .system(Some("aarch64-linux".to_owned()))
+        .build()
+        .unwrap();
+    assert_eq!(
+        build.eval_args(),
+        [
+            "--impure",
+            "--override-input",
+            "nixpkgs",
+            "path:/nixpkgs",
+            "--system",
+            "aarch64-linux"
+        ]
+    );
+}
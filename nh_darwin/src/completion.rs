@@ -1,5 +1,6 @@
+use crate::interface::CompletionShell;
 use crate::*;
-use clap_complete::generate;
+use clap_complete::{generate, Shell};
 use color_eyre::Result;
 use tracing::instrument;
 
@@ -9,7 +10,24 @@ impl NHRunnable for interface::CompletionArgs {
     #[instrument(ret, level = "trace")]
     fn run(&self) -> Result<()> {
         let mut cmd = <NHParser as clap::CommandFactory>::command();
-        generate(self.shell, &mut cmd, NH_NAME, &mut std::io::stdout());
+        let mut stdout = std::io::stdout();
+
+        // The group on the arguments guarantees one of them is set
+        match self.shell.or(self.shell_flag).unwrap() {
+            CompletionShell::Bash => generate(Shell::Bash, &mut cmd, NH_NAME, &mut stdout),
+            CompletionShell::Elvish => generate(Shell::Elvish, &mut cmd, NH_NAME, &mut stdout),
+            CompletionShell::Fish => generate(Shell::Fish, &mut cmd, NH_NAME, &mut stdout),
+            CompletionShell::Nushell => generate(
+                clap_complete_nushell::Nushell,
+                &mut cmd,
+                NH_NAME,
+                &mut stdout,
+            ),
+            CompletionShell::PowerShell => {
+                generate(Shell::PowerShell, &mut cmd, NH_NAME, &mut stdout)
+            }
+            CompletionShell::Zsh => generate(Shell::Zsh, &mut cmd, NH_NAME, &mut stdout),
+        }
         Ok(())
     }
 }
@@ -0,0 +1,85 @@
+use std::{collections::HashMap, io::ErrorKind, path::PathBuf};
+
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Result;
+use tracing::debug;
+
+/// Default values for the command line arguments, read from `$XDG_CONFIG_HOME/nh/config.toml`
+///
+/// The keys are the long names of the arguments, like `diff-provider` or `no-nom`, and apply to
+/// every subcommand accepting them. They are installed as the defaults of the arguments, so the
+/// precedence is: built-in default < config file < environment variable < command line flag.
+#[derive(Debug, Default)]
+pub struct Config(HashMap<String, Vec<String>>);
+
+impl Config {
+    fn path() -> Option<PathBuf> {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .map(|dir| dir.join("nh").join("config.toml"))
+    }
+
+    /// Reads the config file, which is allowed to not exist
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!(?path, "No config file found");
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("Reading {}", path.display()));
+            }
+        };
+
+        let table: toml::Table =
+            toml::from_str(&contents).wrap_err_with(|| format!("Parsing {}", path.display()))?;
+
+        let mut values = HashMap::new();
+        for (key, value) in table {
+            let value = config_values(&key, value)?;
+            values.insert(key, value);
+        }
+
+        Ok(Self(values))
+    }
+
+    /// Sets the values of the config file as the defaults of the matching arguments
+    pub fn apply(&self, cmd: clap::Command) -> clap::Command {
+        let mut cmd = cmd.mut_args(
+            |arg| match arg.get_long().and_then(|long| self.0.get(long)) {
+                Some(values) => arg.default_values(values.clone()),
+                None => arg,
+            },
+        );
+
+        let subcommands: Vec<String> = cmd
+            .get_subcommands()
+            .map(|sub| sub.get_name().to_owned())
+            .collect();
+        for name in subcommands {
+            cmd = cmd.mut_subcommand(name, |sub| self.apply(sub));
+        }
+
+        cmd
+    }
+}
+
+fn config_values(key: &str, value: toml::Value) -> Result<Vec<String>> {
+    match value {
+        toml::Value::String(s) => Ok(vec![s]),
+        toml::Value::Integer(i) => Ok(vec![i.to_string()]),
+        toml::Value::Boolean(b) => Ok(vec![b.to_string()]),
+        toml::Value::Array(values) => Ok(values
+            .into_iter()
+            .map(|value| config_values(key, value))
+            .collect::<Result<Vec<_>>>()?
+            .concat()),
+        other => bail!("Unsupported value for {key:?} in the config file: {other}"),
+    }
+}
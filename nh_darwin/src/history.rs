@@ -0,0 +1,116 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use color_eyre::eyre::{Context, ContextCompat};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// Activation recorded in the history, independently of the generations of the profiles
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: String,
+    /// Subcommand that activated the configuration, like "os switch"
+    pub action: String,
+    pub flakeref: String,
+    /// Name of the configuration, the hostname for os and user@host for home
+    pub configuration: String,
+    pub store_path: String,
+}
+
+impl Entry {
+    pub fn new(action: &str, flakeref: &str, configuration: &str, store_path: &str) -> Self {
+        // Local flakes are recorded by their absolute path, as the working directory changes
+        let flakeref = match fs::canonicalize(flakeref) {
+            Ok(path) => path.display().to_string(),
+            Err(_) => flakeref.to_owned(),
+        };
+
+        Self {
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            action: action.to_owned(),
+            flakeref,
+            configuration: configuration.to_owned(),
+            store_path: store_path.to_owned(),
+        }
+    }
+}
+
+/// History file, under $XDG_STATE_HOME/nh
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map(|dir| dir.join("nh").join("history.jsonl"))
+}
+
+/// Appends the entry to the history, only warning if it can't be written, as the activation
+/// already succeeded
+pub fn record(entry: &Entry) {
+    if let Err(err) = try_record(entry) {
+        warn!("Couldn't record the activation in the history: {err:#}");
+    }
+}
+
+fn try_record(entry: &Entry) -> Result<()> {
+    let path = history_path().context("Neither XDG_STATE_HOME nor HOME is set")?;
+    debug!("Recording {entry:?} in {path:?}");
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Creating {dir:?}"))?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Opening {path:?}"))?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// Reads the entries of the history, oldest first, skipping the lines that can't be parsed
+pub fn read() -> Result<Vec<Entry>> {
+    let Some(path) = history_path() else {
+        return Ok(Vec::new());
+    };
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("Reading {path:?}")),
+    };
+
+    Ok(parse(&content))
+}
+
+fn parse(content: &str) -> Vec<Entry> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                debug!("Skipping the history line {line:?}: {err}");
+                None
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse() {
+    let entry = Entry::new(
+        "os switch",
+        "github:a/b",
+        "host",
+        "/nix/store/abc-nixos-system-host",
+    );
+    let content = format!("{}\nnot json\n\n", serde_json::to_string(&entry).unwrap());
+    let entries = parse(&content);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].action, "os switch");
+    assert_eq!(entries[0].flakeref, "github:a/b");
+    assert_eq!(entries[0].store_path, "/nix/store/abc-nixos-system-host");
+}
@@ -1,23 +1,29 @@
 use std::env;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
-use color_eyre::eyre::bail;
+use color_eyre::eyre::{bail, Context};
 use color_eyre::Result;
 use thiserror::Error;
-use tracing::{debug, info, instrument};
+use tracing::{debug, info, instrument, warn};
 
 use crate::*;
 use crate::{
     interface::NHRunnable,
-    interface::{FlakeRef, HomeArgs, HomeRebuildArgs, HomeSubcommand},
-    util::{compare_semver, get_nix_version},
+    interface::{FlakeRef, GlobalArgs, HomeArgs, HomeRebuildArgs, HomeSubcommand, OutputFormat},
+    util::{get_nix_version, hostname, RebuildSummary},
 };
 
 #[derive(Error, Debug)]
 enum HomeRebuildError {
     #[error("Configuration \"{0}\" doesn't exist")]
     ConfigName(String),
+    #[error(
+        "{0} has no homeConfigurations. If home-manager is a module of the system configuration, \
+        use nh os switch, or nh home switch --via-system"
+    )]
+    NoHomeConfigurations(String),
 }
 
 impl NHRunnable for HomeArgs {
@@ -34,100 +40,251 @@ impl NHRunnable for HomeArgs {
 
 impl HomeRebuildArgs {
     fn rebuild(&self, action: &HomeSubcommand) -> Result<()> {
-        let out_dir = tempfile::Builder::new().prefix("nh-home-").tempdir()?;
-        let out_link = out_dir.path().join("result");
-        let out_link_str = out_link.to_str().unwrap();
-        debug!("out_dir: {:?}", out_dir);
-        debug!("out_link {:?}", out_link);
-
+        // Git works on the directory of local flakes
+        let git_dir = self
+            .flakeref
+            .local_path()
+            .map(|dir| dir.to_string_lossy().into_owned());
         if self.common.pull {
-            commands::CommandBuilder::default()
-                .args(["git", "-C", &self.flakeref, "pull"])
-                .message("Pulling flake")
-                .build()?
-                .exec()?;
+            match &git_dir {
+                Some(git_dir) => commands::CommandBuilder::default()
+                    .args(["git", "-C", git_dir, "pull"])
+                    .message("Pulling flake")
+                    .interactive(true)
+                    .build()?
+                    .exec()?,
+                None => warn!(
+                    "Not pulling {}, which isn't a local flake",
+                    self.flakeref.deref()
+                ),
+            }
         }
 
-        let username = std::env::var("USER").expect("Couldn't get username");
-
-        let hm_config_name = match &self.configuration {
-            Some(name) => {
-                if configuration_exists(&self.flakeref, name)? {
-                    name.to_owned()
-                } else {
-                    return Err(HomeRebuildError::ConfigName(name.to_owned()).into());
-                }
+        if let Some(git_dir) = &git_dir {
+            if self.common.stage_untracked {
+                util::stage_untracked(git_dir, false)?;
             }
-            None => get_home_output(&self.flakeref, &username)?,
-        };
-
-        debug!("hm_config_name: {}", hm_config_name);
+            let refuse = matches!(action, HomeSubcommand::Switch(_)) && self.common.refuse_dirty;
+            util::check_git_tree(git_dir, refuse, self.common.stage_untracked)?;
+        }
 
-        let flakeref = format!(
-            "{}#homeConfigurations.{}.config.home.activationPackage",
-            &self.flakeref.deref(),
-            hm_config_name
-        );
+        let username = std::env::var("USER").expect("Couldn't get username");
 
-        if self.common.update {
+        if self.common.updates_flake() {
             // Get the Nix version
-            let nix_version = get_nix_version().unwrap_or_else(|_| {
-                panic!("Failed to get Nix version. Custom Nix fork?");
-            });
-
-            let status = commands::CommandBuilder::default()
-                .args(["git", "-C", &self.flakeref, "diff", "--name-only", "--diff-filter=U"])
-                .message("Checking for conflicts")
-                .build()?
-                .exec_capture()?;
-
-            if let Some(conflict) = status {
-                if conflict == "flake.lock\n".to_string() {
+            let nix_version = get_nix_version()
+                .map_err(|err| {
+                    warn!("Couldn't detect the Nix version, assuming a recent one: {err}")
+                })
+                .ok();
+
+            let output = match &git_dir {
+                Some(git_dir) => commands::CommandBuilder::default()
+                    .args([
+                        "git",
+                        "-C",
+                        git_dir,
+                        "diff",
+                        "--name-only",
+                        "--diff-filter=U",
+                    ])
+                    .message("Checking for conflicts")
+                    .build()?
+                    .exec_capture_split()?,
+                None => None,
+            };
+
+            match output {
+                Some(output) if !output.status.success() => {
+                    debug!("Not checking for conflicts: {}", output.stderr.trim());
+                }
+                Some(output) if output.stdout == "flake.lock\n" => {
+                    // Only local flakes are checked for conflicts
+                    let git_dir = git_dir.as_deref().unwrap_or_default();
                     commands::CommandBuilder::default()
-                        .args(["git", "-C", &self.flakeref, "reset", "flake.lock"])
+                        .args(["git", "-C", git_dir, "reset", "flake.lock"])
                         .message("Resetting flake.lock")
                         .build()?
                         .exec()?;
                     commands::CommandBuilder::default()
-                        .args(["git", "-C", &self.flakeref, "checkout", "flake.lock"])
+                        .args(["git", "-C", git_dir, "checkout", "flake.lock"])
                         .message("Checking out flake.lock")
                         .build()?
                         .exec()?;
-                } else if conflict != "" {
-                    panic!("Conflicts dectected that were more than just flake.lock, {conflict:?}");
                 }
-            }
-
-            // Default interface for updating flake inputs
-            let mut update_args = vec!["nix", "flake", "update"];
-
-            // If user is on Nix 2.19.0 or above, --flake must be passed
-            if let Ok(ordering) = compare_semver(&nix_version, "2.19.0") {
-                if ordering == std::cmp::Ordering::Greater {
-                    update_args.push("--flake");
+                Some(output) if !output.stdout.is_empty() => {
+                    panic!(
+                        "Conflicts dectected that were more than just flake.lock, {:?}",
+                        output.stdout
+                    );
                 }
+                _ => {}
             }
 
-            update_args.push(&self.flakeref);
+            let update_args = self
+                .common
+                .update_command(&self.flakeref, nix_version.as_deref());
 
             debug!("nix_version: {:?}", nix_version);
             debug!("update_args: {:?}", update_args);
 
             commands::CommandBuilder::default()
                 .args(&update_args)
+                .args(self.common.impure.then_some("--impure"))
+                .args(
+                    self.common
+                        .accept_flake_config
+                        .then_some("--accept-flake-config"),
+                )
+                .args(self.common.refresh.then_some("--refresh"))
+                .args(GlobalArgs::get().nix_verbosity())
                 .message("Updating flake")
                 .build()?
                 .exec()?;
         }
 
+        if self.all {
+            return self.rebuild_all(action, &username);
+        }
+
+        if self.via_system {
+            let hostname = hostname().context("Couldn't get hostname")?;
+            let attr = format!(
+                "{}.{hostname:?}.config.home-manager.users.{username:?}.home.activationPackage",
+                nixos::CONFIGURATION_MODULE
+            );
+            return self.rebuild_configuration(action, &username, &attr, &username);
+        }
+
+        let hm_config_name = match &self.configuration {
+            Some(name) => {
+                if configuration_exists(&self.flakeref, name)? {
+                    name.to_owned()
+                } else {
+                    return Err(HomeRebuildError::ConfigName(name.to_owned()).into());
+                }
+            }
+            None => get_home_output(&self.flakeref, &username)?,
+        };
+
+        self.rebuild_configuration(
+            action,
+            &hm_config_name,
+            &activation_attr(&hm_config_name),
+            &username,
+        )
+    }
+
+    /// Rebuilds every configuration of the flake, reporting the failed ones at the end
+    fn rebuild_all(&self, action: &HomeSubcommand, username: &str) -> Result<()> {
+        let names = configuration_names(&self.flakeref)?;
+        let mut failed = Vec::new();
+
+        for name in &names {
+            if let Err(err) =
+                self.rebuild_configuration(action, name, &activation_attr(name), username)
+            {
+                warn!("Failed to rebuild configuration {name:?}");
+                for e in err.chain() {
+                    warn!("{}", e);
+                }
+                failed.push(name.as_str());
+            }
+        }
+
+        use owo_colors::OwoColorize;
+        println!();
+        for name in &names {
+            if failed.contains(&name.as_str()) {
+                println!("- {} {}", "FAIL".red(), name);
+            } else {
+                println!("- {} {}", "OK  ".green(), name);
+            }
+        }
+
+        if !failed.is_empty() {
+            bail!(
+                "Failed to rebuild {} of {} configurations",
+                failed.len(),
+                names.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Rebuilds the configuration from its activation package, at the attribute of the flake
+    fn rebuild_configuration(
+        &self,
+        action: &HomeSubcommand,
+        hm_config_name: &str,
+        attr: &str,
+        username: &str,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let json = self.common.output_format == OutputFormat::Json;
+        if json {
+            commands::keep_stdout_clean();
+        }
+        let mut summary = RebuildSummary {
+            action: match action {
+                HomeSubcommand::Build(_) => "build".to_owned(),
+                _ => "switch".to_owned(),
+            },
+            hostname: hm_config_name.to_owned(),
+            flakeref: self.flakeref.deref().to_owned(),
+            ..Default::default()
+        };
+
+        self.rebuild_configuration_inner(action, hm_config_name, attr, username, &mut summary)?;
+
+        if json {
+            summary.print(start)?;
+        }
+        Ok(())
+    }
+
+    fn rebuild_configuration_inner(
+        &self,
+        action: &HomeSubcommand,
+        hm_config_name: &str,
+        attr: &str,
+        username: &str,
+        summary: &mut RebuildSummary,
+    ) -> Result<()> {
+        let out_dir = tempfile::Builder::new().prefix("nh-home-").tempdir()?;
+        let _lock = util::try_lock_dir(out_dir.path(), false)?;
+        let out_link = out_dir.path().join("result");
+        let out_link_str = out_link.to_str().unwrap();
+        debug!("out_dir: {:?}", out_dir);
+        debug!("out_link {:?}", out_link);
+
+        debug!("hm_config_name: {}", hm_config_name);
+
+        let flakeref = format!("{}#{attr}", &self.flakeref.deref());
+
         commands::BuildCommandBuilder::default()
             .flakeref(&flakeref)
             .extra_args(["--out-link", out_link_str])
+            .extra_args(self.common.build_args())
             .extra_args(&self.extra_args)
             .message("Building home configuration")
-            .nom(!self.common.no_nom)
+            .nom(self.common.use_nom())
+            .retries(self.common.retries)
             .build()?
-            .exec()?;
+            .exec()
+            .wrap_err(PhaseError::Build)?;
+
+        let dry_run_commands = GlobalArgs::get().dry_run_commands;
+        if !dry_run_commands {
+            summary.store_path = Some(std::fs::read_link(&out_link)?.display().to_string());
+        }
+
+        // The summary already has the store path, and is the only output on stdout
+        let human = self.common.output_format == OutputFormat::Human;
+        if self.common.print_out_path && human && !dry_run_commands {
+            println!("{}", std::fs::read_link(&out_link)?.display());
+        }
 
         let prev_generation: Option<PathBuf> = [
             PathBuf::from("/nix/var/nix/profiles/per-user")
@@ -142,14 +299,39 @@ impl HomeRebuildArgs {
 
         debug!("prev_generation: {:?}", prev_generation);
 
-        // just do nothing for None case (fresh installs)
-        if let Some(prev_gen) = prev_generation {
+        summary.changed = match &prev_generation {
+            Some(prev_gen) => match (
+                std::fs::canonicalize(prev_gen),
+                std::fs::canonicalize(&out_link),
+            ) {
+                (Ok(prev), Ok(new)) => prev != new,
+                _ => true,
+            },
+            None => true,
+        };
+
+        let show_diff = |prev_gen: &Path| {
             commands::CommandBuilder::default()
-                .args(self.common.diff_provider.split_ascii_whitespace())
+                .args(util::diff_args(&self.common.diff_provider))
                 .args([(prev_gen.to_str().unwrap()), out_link_str])
                 .message("Comparing changes")
                 .build()?
-                .exec()?;
+                .exec()
+                .wrap_err(PhaseError::Diff)
+        };
+        let show_closure = |prev_gen: &Path| {
+            commands::CommandBuilder::default()
+                .args(["nix", "store", "diff-closures"])
+                .args([(prev_gen.to_str().unwrap()), out_link_str])
+                .message("Comparing the closures")
+                .build()?
+                .exec()
+                .wrap_err(PhaseError::Diff)
+        };
+
+        // just do nothing for None case (fresh installs)
+        if let Some(prev_gen) = &prev_generation {
+            show_diff(prev_gen)?;
         }
 
         if self.common.dry || matches!(action, HomeSubcommand::Build(_)) {
@@ -157,11 +339,16 @@ impl HomeRebuildArgs {
         }
 
         if self.common.ask {
-            info!("Apply the config?");
-            let confirmation = dialoguer::Confirm::new().default(false).interact()?;
+            let prompt = format!("Apply the config {hm_config_name}?");
+            let confirmation = match &prev_generation {
+                Some(prev_gen) => {
+                    util::confirm_diff(&prompt, || show_diff(prev_gen), || show_closure(prev_gen))?
+                }
+                None => util::confirm(&prompt)?,
+            };
 
             if !confirmation {
-                return Ok(());
+                bail!(PhaseError::Declined);
             }
         }
 
@@ -174,7 +361,18 @@ impl HomeRebuildArgs {
             .args([&format!("{}/activate", out_link_str)])
             .message("Activating configuration")
             .build()?
-            .exec()?;
+            .exec()
+            .wrap_err(PhaseError::Activation)?;
+
+        if !GlobalArgs::get().dry_run_commands {
+            let store_path = std::fs::read_link(&out_link)?;
+            history::record(&history::Entry::new(
+                "home switch",
+                &self.flakeref,
+                hm_config_name,
+                &store_path.to_string_lossy(),
+            ));
+        }
 
         // Drop the out dir *only* when we are finished
         drop(out_dir);
@@ -183,6 +381,11 @@ impl HomeRebuildArgs {
     }
 }
 
+/// Attribute of the activation package of a standalone home configuration
+fn activation_attr(hm_config_name: &str) -> String {
+    format!("homeConfigurations.{hm_config_name}.config.home.activationPackage")
+}
+
 fn get_home_output<S: AsRef<str> + std::fmt::Display>(
     flakeref: &FlakeRef,
     username: S,
@@ -190,10 +393,7 @@ fn get_home_output<S: AsRef<str> + std::fmt::Display>(
     // Replicate these heuristics
     // https://github.com/nix-community/home-manager/blob/433e8de330fd9c157b636f9ccea45e3eeaf69ad2/home-manager/home-manager#L110
 
-    let hostname = hostname::get()
-        .expect("Couldn't get hostname")
-        .into_string()
-        .unwrap();
+    let hostname = hostname().context("Couldn't get hostname")?;
 
     let username_hostname = format!("{}@{}", username, &hostname);
 
@@ -209,17 +409,43 @@ fn get_home_output<S: AsRef<str> + std::fmt::Display>(
     }
 }
 
-#[instrument(ret, err, level = "debug")]
-fn configuration_exists(flakeref: &FlakeRef, configuration: &str) -> Result<bool> {
+/// Evaluates the homeConfigurations of the flake with the function, telling apart the flakes
+/// that don't have any, like the ones using home-manager as a module of the system
+fn eval_home_configurations(flakeref: &FlakeRef, args: &[&str], apply: &str) -> Result<String> {
     let output = format!("{}#homeConfigurations", flakeref.deref());
-    let filter = format!(r#" x: x ? "{}" "#, configuration);
 
     let result = commands::CommandBuilder::default()
-        .args(["nix", "eval", &output, "--apply", &filter])
+        .args(["nix", "eval"])
+        .args(args)
+        .args([&output, "--apply", apply])
         .build()?
-        .exec_capture()?
+        .exec_capture_split()?
         .unwrap();
 
+    if result.status.success() {
+        return Ok(result.stdout);
+    }
+    if result.stderr.contains("does not provide attribute") {
+        bail!(HomeRebuildError::NoHomeConfigurations(
+            flakeref.deref().to_owned()
+        ));
+    }
+    bail!("Failed to evaluate {output}:\n{}", result.stderr.trim());
+}
+
+#[instrument(ret, err, level = "debug")]
+fn configuration_names(flakeref: &FlakeRef) -> Result<Vec<String>> {
+    let result = eval_home_configurations(flakeref, &["--json"], "builtins.attrNames")?;
+
+    serde_json::from_str(&result)
+        .with_context(|| format!("Failed to parse nix-eval output: {result}"))
+}
+
+#[instrument(ret, err, level = "debug")]
+fn configuration_exists(flakeref: &FlakeRef, configuration: &str) -> Result<bool> {
+    let filter = format!(r#" x: x ? "{}" "#, configuration);
+    let result = eval_home_configurations(flakeref, &[], &filter)?;
+
     debug!(?result);
 
     match result.as_str().trim() {
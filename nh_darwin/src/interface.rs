@@ -1,8 +1,12 @@
 use ambassador::{delegatable_trait, Delegate};
 use anstyle::Style;
-use clap::{builder::Styles, Args, Parser, Subcommand};
+use clap::{
+    builder::{PossibleValue, Styles, TypedValueParser},
+    Args, Parser, Subcommand, ValueEnum,
+};
 use color_eyre::Result;
-use std::{ffi::OsString, ops::Deref, path::Path, path::PathBuf};
+use once_cell::sync::OnceCell;
+use std::{ffi::OsString, io::IsTerminal, ops::Deref, path::Path, path::PathBuf};
 
 #[derive(Debug, Clone, Default)]
 pub struct FlakeRef(String);
@@ -31,6 +35,59 @@ impl Deref for FlakeRef {
     }
 }
 
+impl FlakeRef {
+    /// Directory of the flake when it's a local one, given as a path or a `path:` or `git+file:`
+    /// URL, without the attribute after `#` and with the `?dir=` subdirectory applied
+    pub fn local_path(&self) -> Option<PathBuf> {
+        let reference = self
+            .0
+            .split_once('#')
+            .map_or(self.0.as_str(), |(reference, _)| reference);
+        let (base, query) = reference.split_once('?').unwrap_or((reference, ""));
+
+        let path = if let Some(path) = ["git+file://", "git+file:", "path:"]
+            .iter()
+            .find_map(|scheme| base.strip_prefix(scheme))
+        {
+            PathBuf::from(path)
+        } else if base.contains(':') {
+            // Remote flakes, like github: or git+https:
+            return None;
+        } else if base.contains('/') || base.starts_with('.') || Path::new(base).is_dir() {
+            PathBuf::from(base)
+        } else {
+            // Flakes from the registry, like nixpkgs
+            return None;
+        };
+
+        let dir = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("dir="));
+        Some(match dir {
+            Some(dir) => path.join(dir),
+            None => path,
+        })
+    }
+}
+
+/// Default flakeref from the NH_FLAKE or FLAKE environment variables, with a fallback otherwise
+fn default_flake(fallback: &str) -> String {
+    std::env::var("NH_FLAKE")
+        .or_else(|_| std::env::var("FLAKE"))
+        .unwrap_or_else(|_| fallback.to_owned())
+}
+
+/// Whether the value of NH_NOTIFY enables the notifications, taking the same values as the boolean
+/// environment variables of clap
+fn notify_env(value: Option<&str>) -> bool {
+    value.is_some_and(|value| {
+        matches!(
+            value.to_lowercase().as_str(),
+            "y" | "yes" | "t" | "true" | "on" | "1"
+        )
+    })
+}
+
 fn make_style() -> Styles {
     Styles::plain().header(Style::new().bold()).literal(
         Style::new()
@@ -49,14 +106,99 @@ fn make_style() -> Styles {
 )]
 /// nh is yet another nix helper
 pub struct NHParser {
-    #[arg(short, long, global = true)]
-    /// Show debug logs
-    pub verbose: bool,
+    #[command(flatten)]
+    pub global: GlobalArgs,
 
     #[command(subcommand)]
     pub command: NHCommand,
 }
 
+static GLOBAL_ARGS: OnceCell<GlobalArgs> = OnceCell::new();
+
+/// Arguments shared by every subcommand, available anywhere through [`GlobalArgs::get`]
+#[derive(Args, Debug, Clone, Default)]
+pub struct GlobalArgs {
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    /// Show debug logs, and increase the verbosity of nix by as many levels
+    pub verbose: u8,
+
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    /// Only show warnings and errors, the log file still gets everything
+    pub quiet: bool,
+
+    #[arg(long, global = true)]
+    /// Print the commands that would change the system instead of running them
+    pub dry_run_commands: bool,
+
+    #[arg(long, global = true)]
+    /// Kill commands that run longer than this, except for builds. E.g. 300s, 5m
+    pub command_timeout: Option<humantime::Duration>,
+
+    #[arg(long, global = true, value_hint = clap::ValueHint::FilePath)]
+    /// Also append the logs to this file, always including the debug ones
+    pub log_file: Option<PathBuf>,
+
+    #[arg(long, global = true, value_enum, default_value_t = ColorMode::Auto)]
+    /// When to color the output, auto colors it on a terminal unless NO_COLOR is set
+    pub color: ColorMode,
+
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Human)]
+    /// Format of the logs on stderr
+    pub log_format: LogFormat,
+
+    #[arg(long, short = 'y', global = true)]
+    /// Answer yes to every confirmation prompt, without asking
+    pub yes: bool,
+
+    #[arg(long, global = true)]
+    /// Make yes the default answer of confirmation prompts
+    pub default_yes: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Human,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorMode {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl GlobalArgs {
+    /// Makes the parsed arguments available through [`GlobalArgs::get`]
+    pub fn install(self) {
+        GLOBAL_ARGS
+            .set(self)
+            .expect("Global arguments were already installed");
+    }
+
+    /// Gets the global arguments, or their defaults if they weren't installed
+    pub fn get() -> &'static GlobalArgs {
+        GLOBAL_ARGS.get_or_init(GlobalArgs::default)
+    }
+
+    /// Whether the logs on stderr should be colored
+    pub fn use_color(&self) -> bool {
+        match self.color {
+            ColorMode::Auto => !crate::util::no_color() && std::io::stderr().is_terminal(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+
+    /// Verbosity flags for nix commands, one per level
+    pub fn nix_verbosity(&self) -> Vec<&'static str> {
+        vec!["-v"; self.verbose as usize]
+    }
+}
+
 #[delegatable_trait]
 pub trait NHRunnable {
     fn run(&self) -> Result<()>;
@@ -73,6 +215,54 @@ pub enum NHCommand {
     Completions(CompletionArgs),
 }
 
+impl NHCommand {
+    /// External programs the command runs, checked before starting it
+    pub fn required_tools(&self) -> Vec<String> {
+        let mut tools = Vec::new();
+        match self {
+            NHCommand::Os(args) => {
+                tools.push("nix".to_owned());
+                match &args.action {
+                    OsRebuildType::Switch(args)
+                    | OsRebuildType::Boot(args)
+                    | OsRebuildType::Test(args)
+                    | OsRebuildType::Build(args)
+                    | OsRebuildType::DryActivate(args) => tools.extend(args.required_tools()),
+                    OsRebuildType::BuildVm(args) => match args.common.builder {
+                        Builder::Nh => tools.extend(args.common.common.required_tools()),
+                        Builder::NixosRebuild => {
+                            tools.push(crate::nixos::REBUILD_PROGRAM.to_owned())
+                        }
+                    },
+                    OsRebuildType::Rollback(args) => {
+                        tools.push("nix-env".to_owned());
+                        tools.extend(diff_tool(&args.diff_provider));
+                    }
+                    OsRebuildType::Diff(args) => tools.extend(diff_tool(&args.diff_provider)),
+                    _ => {}
+                }
+            }
+            NHCommand::Home(args) => {
+                tools.push("nix".to_owned());
+                if let HomeSubcommand::Switch(args) | HomeSubcommand::Build(args) = &args.subcommand
+                {
+                    tools.extend(args.common.required_tools());
+                }
+            }
+            NHCommand::Search(_) | NHCommand::Clean(_) => tools.push("nix".to_owned()),
+            NHCommand::Completions(_) => {}
+        }
+        tools.sort();
+        tools.dedup();
+        tools
+    }
+}
+
+/// Program running the closure diffs of the provider
+fn diff_tool(provider: &str) -> Option<String> {
+    crate::util::diff_args(provider).into_iter().next()
+}
+
 #[derive(Args, Debug)]
 #[clap(verbatim_doc_comment)]
 /// NixOS / nix-darwin functionality
@@ -93,43 +283,295 @@ pub enum OsRebuildType {
     Test(OsRebuildArgs),
     /// Build the new configuration
     Build(OsRebuildArgs),
+    /// Build the new configuration and show what activating it would change
+    DryActivate(OsRebuildArgs),
+    /// Build a virtual machine running the new configuration
+    BuildVm(OsBuildVmArgs),
+    /// Activate a previous generation, without rebuilding
+    Rollback(OsRollbackArgs),
+    /// List the generations of the system profile
+    ListGenerations(OsListGenerationsArgs),
+    /// Compare two generations of the system profile
+    Diff(OsDiffArgs),
+    /// Load the configuration in nix repl
+    Repl(OsReplArgs),
+    /// Show the recent activations of os and home configurations
+    History(OsHistoryArgs),
     /// Show an overview of the system's info
     #[command(hide = true)]
     Info,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 pub struct OsRebuildArgs {
     #[command(flatten)]
     pub common: CommonRebuildArgs,
 
     /// Flake reference to build
     #[cfg(target_os = "linux")]
-    #[arg(env = "FLAKE", value_hint = clap::ValueHint::DirPath, default_value = "/etc/nixos")]
+    #[arg(env = "NH_OS_FLAKE", value_hint = clap::ValueHint::DirPath, default_value = default_flake("/etc/nixos"))]
     pub flakeref: FlakeRef,
     /// Flake reference to build
     #[cfg(target_os = "macos")]
-    #[arg(env = "FLAKE", value_hint = clap::ValueHint::DirPath, default_value = "~/.nixpkgs")]
+    #[arg(env = "NH_OS_FLAKE", value_hint = clap::ValueHint::DirPath, default_value = default_flake("~/.nixpkgs"))]
     pub flakeref: FlakeRef,
 
     /// Output to choose from the flakeref. Hostname is used by default
-    #[arg(long, short = 'H', global = true)]
-    pub hostname: Option<OsString>,
+    ///
+    /// Build accepts several, comma separated or repeated, and builds each of them
+    #[arg(long, short = 'H', global = true, value_delimiter = ',')]
+    pub hostname: Vec<OsString>,
+
+    /// Number of configurations to build at once when building several hostnames
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: Option<u32>,
+
+    /// Attribute path of the configuration in the flake, like nixosConfigurations.myhost
+    ///
+    /// Overrides the path derived from the hostname
+    #[arg(long, conflicts_with = "hostname")]
+    pub configuration_attr: Option<String>,
+
+    /// Don't check that the configuration for the hostname exists before building
+    #[arg(long)]
+    pub no_hostname_check: bool,
 
     /// Name of the specialisation
-    #[arg(long, short)]
+    #[arg(long, short, value_parser = SpecialisationParser, hide_possible_values = true)]
     pub specialisation: Option<String>,
 
     /// Don't use specialisations
     #[arg(long, short = 'S')]
     pub no_specialisation: bool,
 
+    /// Build a channels-based configuration instead of a flake
+    ///
+    /// This is the default for a directory without a flake.nix
+    #[arg(long)]
+    pub no_flake: bool,
+
+    /// Build the configuration on a remote host over SSH, like user@host
+    #[arg(long)]
+    pub build_host: Option<String>,
+
+    /// Deploy the configuration to a remote host over SSH, like user@host
+    #[arg(long)]
+    pub target_host: Option<String>,
+
+    /// System to build the configuration for, like aarch64-linux, with a remote builder or
+    /// emulation. Only --target-host can activate it
+    #[arg(long, value_name = "SYSTEM")]
+    pub system: Option<String>,
+
+    /// Warn before building when the filesystem of the store has less free space than this
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "5GiB")]
+    pub warn_free: u64,
+
+    /// Fail before building when the filesystem of the store has less free space than this,
+    /// like 10GiB or 500M
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_free: Option<u64>,
+
+    /// Name of the system profile to use, under /nix/var/nix/profiles/system-profiles
+    #[arg(long, short = 'P')]
+    pub profile_name: Option<String>,
+
+    /// Only keep this number of generations of the system profile after activating, at least
+    /// 2 so that the previous one is still there to roll back to
+    #[arg(long, value_parser = clap::value_parser!(u32).range(2..))]
+    pub keep_generations: Option<u32>,
+
+    /// Force the reinstallation of the bootloader
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    pub install_bootloader: bool,
+
+    /// Reboot after adding the configuration to the bootloader, asking for confirmation first
+    #[arg(long)]
+    pub reboot: bool,
+
+    /// Keep the result symlink of the build when the rebuild fails, instead of removing it
+    #[arg(long)]
+    pub keep_result_on_failure: bool,
+
+    /// Print the time spent in each phase of the rebuild
+    #[arg(long)]
+    pub timings: bool,
+
+    /// Sign the built closure with this secret key file before deploying it
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub sign_with: Option<PathBuf>,
+
+    /// Commit the updated flake.lock after a successful activation
+    #[arg(long, requires = "updating")]
+    pub commit_lock: bool,
+
+    /// Roll the profile back and activate the previous configuration if the activation fails
+    #[arg(long)]
+    pub rollback_on_failure: bool,
+
+    /// Activate the configuration even when the system is already running it
+    #[arg(long)]
+    pub force: bool,
+
+    /// Program building and activating the configuration, nixos-rebuild (or darwin-rebuild)
+    /// replaces nh's own steps after updating the flake
+    #[arg(long, value_enum, default_value_t = Builder::Nh)]
+    pub builder: Builder,
+
+    /// System to compare the new configuration to, booted shows what changed since the boot
+    #[arg(long, value_enum, default_value_t = DiffAgainst::Current)]
+    pub diff_against: DiffAgainst,
+
+    /// Set for each build of several hostnames, whose shared flake was already checked once
+    #[arg(skip)]
+    pub skip_git_checks: bool,
+
     /// Extra arguments passed to nix build
     #[arg(last = true)]
     pub extra_args: Vec<String>,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Builder {
+    #[default]
+    Nh,
+    #[value(alias = "darwin-rebuild")]
+    NixosRebuild,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DiffAgainst {
+    #[default]
+    Current,
+    Booted,
+}
+
+/// Accepts any specialisation name, offering the ones of the running system for completion
+#[derive(Debug, Clone)]
+struct SpecialisationParser;
+
+impl TypedValueParser for SpecialisationParser {
+    type Value = String;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        clap::builder::StringValueParser::new().parse_ref(cmd, arg, value)
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        let names = crate::nixos::specialisations(Path::new(crate::nixos::CURRENT_PROFILE));
+        Some(Box::new(names.into_iter().map(PossibleValue::new)))
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct OsBuildVmArgs {
+    #[command(flatten)]
+    pub common: OsRebuildArgs,
+
+    /// Run the virtual machine after building it
+    #[arg(long)]
+    pub run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OsReplArgs {
+    /// Flake reference to load
+    #[cfg(target_os = "linux")]
+    #[arg(env = "NH_OS_FLAKE", value_hint = clap::ValueHint::DirPath, default_value = default_flake("/etc/nixos"))]
+    pub flakeref: FlakeRef,
+    /// Flake reference to load
+    #[cfg(target_os = "macos")]
+    #[arg(env = "NH_OS_FLAKE", value_hint = clap::ValueHint::DirPath, default_value = default_flake("~/.nixpkgs"))]
+    pub flakeref: FlakeRef,
+
+    /// Output to choose from the flakeref. Hostname is used by default
+    #[arg(long, short = 'H')]
+    pub hostname: Option<OsString>,
+
+    /// Load the whole flake instead of the configuration of the hostname
+    #[arg(long, conflicts_with = "hostname")]
+    pub whole_flake: bool,
+
+    /// Load the configuration from the channels instead of a flake
+    #[arg(long)]
+    pub no_flake: bool,
+}
+
 #[derive(Debug, Args)]
+pub struct OsRollbackArgs {
+    /// Only print actions, without performing them
+    #[arg(long, short = 'n')]
+    pub dry: bool,
+
+    /// Ask for confirmation
+    #[arg(long, short)]
+    pub ask: bool,
+
+    /// Generation number to roll back to, instead of the previous one
+    #[arg(long)]
+    pub to: Option<u32>,
+
+    /// Closure diff provider, either `nix`, `nvd` or a custom command line
+    #[arg(
+        long,
+        short = 'D',
+        env = "NH_DIFF_PROVIDER",
+        default_value = "nvd diff"
+    )]
+    pub diff_provider: String,
+}
+
+#[derive(Debug, Args)]
+pub struct OsHistoryArgs {
+    /// Number of activations to show, newest first
+    #[arg(long, short = 'n', default_value = "20")]
+    pub limit: usize,
+
+    /// Print the activations as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OsListGenerationsArgs {
+    /// Print the generations as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OsDiffArgs {
+    /// Generation to compare from: a generation number, "current" or "booted"
+    pub from: String,
+
+    /// Generation to compare to: a generation number, "current" or "booted"
+    #[arg(default_value = "current")]
+    pub to: String,
+
+    /// Closure diff provider, either `nix`, `nvd` or a custom command line
+    #[arg(
+        long,
+        short = 'D',
+        env = "NH_DIFF_PROVIDER",
+        default_value = "nvd diff"
+    )]
+    pub diff_provider: String,
+}
+
+#[derive(Debug, Clone, Args)]
 pub struct CommonRebuildArgs {
     /// Only print actions, without performing them
     #[arg(long, short = 'n')]
@@ -140,18 +582,89 @@ pub struct CommonRebuildArgs {
     pub ask: bool,
 
     /// Update flake inputs before building specified configuration
-    #[arg(long, short = 'u')]
+    #[arg(long, short = 'u', group = "updating")]
     pub update: bool,
 
+    /// Update only this flake input before building. Can be repeated
+    #[arg(long, value_name = "NAME", group = "updating", action = clap::ArgAction::Append)]
+    pub update_input: Vec<String>,
+
     /// Run git pull on the flake before building specified configuration
     #[arg(long, short = 'p')]
     pub pull: bool,
 
+    /// Refuse to switch to a local flake with uncommitted changes, instead of only warning about
+    /// them, for reproducible switches
+    #[arg(long)]
+    pub refuse_dirty: bool,
+
+    /// Mark the untracked files of a local flake with git add --intent-to-add, so that the flake
+    /// sees them without committing them
+    #[arg(long)]
+    pub stage_untracked: bool,
+
     /// Don't use nix-output-monitor for the build process
     #[arg(long)]
     pub no_nom: bool,
 
-    /// Closure diff provider
+    /// Use nix-output-monitor even when the output isn't a terminal, like when piping to a log
+    #[arg(long, conflicts_with = "no_nom")]
+    pub force_nom: bool,
+
+    /// Number of times to retry a failed build, waiting exponentially longer between attempts
+    #[arg(long, default_value = "0")]
+    pub retries: u32,
+
+    /// Print the store path of the built configuration
+    #[arg(long)]
+    pub print_out_path: bool,
+
+    /// Format of the result, json prints a summary as the only output on stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output_format: OutputFormat,
+
+    /// Send a desktop notification when the rebuild finishes, also enabled by setting NH_NOTIFY
+    #[arg(long, default_value_t = notify_env(std::env::var("NH_NOTIFY").ok().as_deref()))]
+    pub notify: bool,
+
+    /// Allow the evaluation to read the environment and paths outside the store
+    ///
+    /// This gives up the reproducibility of the configuration
+    #[arg(long)]
+    pub impure: bool,
+
+    /// Fetch the flake and its inputs again instead of using the cached ones
+    #[arg(long)]
+    pub refresh: bool,
+
+    /// Accept the nixConfig of the flake without prompting, like its extra substituters
+    ///
+    /// This lets the flake pick the substituters and keys nix trusts, so only use it with flakes
+    /// you trust
+    #[arg(long)]
+    pub accept_flake_config: bool,
+
+    /// Number of derivations nix builds at once locally, or "auto" for the number of CPUs
+    #[arg(long, value_parser = parse_max_jobs)]
+    pub max_jobs: Option<String>,
+
+    /// Number of cores each derivation may use while building, 0 for all of them
+    #[arg(long)]
+    pub cores: Option<u32>,
+
+    /// Extra binary cache to substitute from during the build. Can be repeated
+    #[arg(long = "substituters", value_name = "URL")]
+    pub substituters: Vec<String>,
+
+    /// Extra public key to trust the binary caches signed with. Can be repeated
+    #[arg(long = "trusted-public-keys", value_name = "KEY")]
+    pub trusted_public_keys: Vec<String>,
+
+    /// Override a flake input with another flakeref for the build. Can be repeated
+    #[arg(long, num_args = 2, value_names = ["NAME", "FLAKEREF"])]
+    pub override_input: Vec<String>,
+
+    /// Closure diff provider, either `nix`, `nvd` or a custom command line
     ///
     /// Default is "nvd diff", but "nix store diff-closures" is also supported
     #[arg(
@@ -163,6 +676,165 @@ pub struct CommonRebuildArgs {
     pub diff_provider: String,
 }
 
+impl OsRebuildArgs {
+    /// External programs the rebuild runs, besides nix. The profile is set and the diff shown
+    /// on the target host, and nixos-rebuild does all of it by itself
+    fn required_tools(&self) -> Vec<String> {
+        match self.builder {
+            Builder::NixosRebuild => vec![crate::nixos::REBUILD_PROGRAM.to_owned()],
+            Builder::Nh if self.target_host.is_some() => match self.common.use_nom() {
+                true => vec!["nom".to_owned()],
+                false => vec![],
+            },
+            Builder::Nh => {
+                let mut tools = vec!["nix-env".to_owned()];
+                tools.extend(self.common.required_tools());
+                tools
+            }
+        }
+    }
+}
+
+impl CommonRebuildArgs {
+    /// Whether to build through nom, which is only useful on a terminal unless forced. Its
+    /// progress goes to stderr, so piping stdout into a log keeps it
+    pub fn use_nom(&self) -> bool {
+        !self.no_nom && (self.force_nom || std::io::stderr().is_terminal())
+    }
+
+    /// External programs the rebuild runs, besides nix
+    fn required_tools(&self) -> Vec<String> {
+        let mut tools: Vec<String> = diff_tool(&self.diff_provider).into_iter().collect();
+        if self.use_nom() {
+            tools.push("nom".to_owned());
+        }
+        tools
+    }
+
+    /// Whether the flake inputs are updated before building, all of them or only some
+    pub fn updates_flake(&self) -> bool {
+        self.update || !self.update_input.is_empty()
+    }
+
+    /// Command updating the flake inputs, only the ones of --update-input when given
+    ///
+    /// An unknown Nix version is assumed to be a recent one.
+    pub fn update_command(&self, flakeref: &str, nix_version: Option<&str>) -> Vec<String> {
+        // Nix 2.19.0 made nix flake update take the inputs to update, and the flake with --flake
+        let new_interface = match nix_version.map(|v| crate::util::compare_semver(v, "2.19.0")) {
+            Some(Ok(ordering)) => ordering != std::cmp::Ordering::Less,
+            _ => true,
+        };
+
+        let mut args: Vec<String> = ["nix", "flake"].map(String::from).into();
+        if new_interface {
+            args.push("update".to_owned());
+            args.extend(self.update_input.iter().cloned());
+            args.extend(["--flake".to_owned(), flakeref.to_owned()]);
+        } else if self.update_input.is_empty() {
+            args.extend(["update".to_owned(), flakeref.to_owned()]);
+        } else {
+            args.extend(["lock".to_owned(), flakeref.to_owned()]);
+            for input in &self.update_input {
+                args.extend(["--update-input".to_owned(), input.clone()]);
+            }
+        }
+        args
+    }
+
+    /// Extra arguments for the nix build, derived from the passthrough flags
+    pub fn build_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = GlobalArgs::get()
+            .nix_verbosity()
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        if self.impure {
+            args.push("--impure".to_owned());
+        }
+
+        if self.refresh {
+            args.push("--refresh".to_owned());
+        }
+
+        if self.accept_flake_config {
+            args.push("--accept-flake-config".to_owned());
+        }
+
+        if let Some(max_jobs) = &self.max_jobs {
+            args.extend(["--max-jobs".to_owned(), max_jobs.clone()]);
+        }
+
+        if let Some(cores) = self.cores {
+            args.extend(["--cores".to_owned(), cores.to_string()]);
+        }
+
+        // nix takes space separated lists for both settings
+        if !self.substituters.is_empty() {
+            args.extend([
+                "--option".to_owned(),
+                "extra-substituters".to_owned(),
+                self.substituters.join(" "),
+            ]);
+        }
+
+        if !self.trusted_public_keys.is_empty() {
+            args.extend([
+                "--option".to_owned(),
+                "extra-trusted-public-keys".to_owned(),
+                self.trusted_public_keys.join(" "),
+            ]);
+        }
+
+        // clap guarantees override_input always comes in NAME FLAKEREF pairs
+        for pair in self.override_input.chunks_exact(2) {
+            args.extend([
+                "--override-input".to_owned(),
+                pair[0].clone(),
+                pair[1].clone(),
+            ]);
+        }
+
+        args
+    }
+}
+
+fn parse_max_jobs(value: &str) -> Result<String, String> {
+    match value == "auto" || value.parse::<u32>().is_ok() {
+        true => Ok(value.to_owned()),
+        false => Err("expected a number or \"auto\"".to_owned()),
+    }
+}
+
+/// Parses a size in bytes, with an optional binary unit like 5GiB, 5G or 500M
+///
+/// Fractions like 1.5G are computed as floating point, and the result is rounded down to whole
+/// bytes.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" | "kb" => 1 << 10,
+        "m" | "mib" | "mb" => 1 << 20,
+        "g" | "gib" | "gb" => 1 << 30,
+        "t" | "tib" | "tb" => 1 << 40,
+        _ => {
+            return Err(format!(
+                "unknown unit {unit:?}, expected one of B, K, M, G or T"
+            ))
+        }
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("expected a size like 5GiB, got {value:?}"))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
 #[derive(Args, Debug)]
 /// Searches packages by querying search.nixos.org
 pub struct SearchArgs {
@@ -180,6 +852,52 @@ pub struct SearchArgs {
     #[arg(short, long, env = "FLAKE", value_hint = clap::ValueHint::DirPath)]
     /// Flake to read what nixpkgs channels to search for
     pub flake: Option<FlakeRef>,
+
+    #[arg(long, short = 'F', value_name = "FLAKEREF", conflicts_with = "channel")]
+    /// Search the packages of this flake locally with nix search, instead of search.nixos.org
+    pub search_flake: Option<FlakeRef>,
+
+    #[arg(long, value_enum, default_value_t = SearchSort::Relevance)]
+    /// Order of the results
+    pub sort: SearchSort,
+
+    #[arg(long)]
+    /// Print the results as a JSON array, in the order of --sort
+    pub json: bool,
+
+    #[arg(long, conflicts_with_all = ["json", "search_flake"])]
+    /// Pick one of the results and open its homepage in the browser
+    pub open: bool,
+
+    #[arg(long, short, conflicts_with_all = ["json", "open"])]
+    /// Filter the results interactively, printing the attribute of the chosen one
+    pub interactive: bool,
+
+    #[arg(long, conflicts_with_all = ["search_flake", "open", "interactive"])]
+    /// Search NixOS options instead of packages
+    pub options: bool,
+
+    #[arg(long, conflicts_with_all = ["search_flake", "open", "interactive", "options"])]
+    /// Search home-manager options instead of packages, building its options documentation
+    pub hm_options: bool,
+
+    #[arg(long, default_value = "1h")]
+    /// How long to reuse the cached results of the same query
+    pub cache_ttl: humantime::Duration,
+
+    #[arg(long)]
+    /// Always query search.nixos.org, ignoring the cached results
+    pub no_cache: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SearchSort {
+    /// As ranked by the search backend, the most relevant printed last
+    Relevance,
+    /// By attribute name
+    Name,
+    /// By version, newest first
+    Version,
 }
 
 // Needed a struct to have multiple sub-subcommands
@@ -215,8 +933,16 @@ pub struct CleanArgs {
     /// At least keep gcroots and generations in this time range since now.
     pub keep_since: humantime::Duration,
 
-    /// Only print actions, without performing them
-    #[arg(long, short = 'n')]
+    #[arg(long, value_name = "NUMBER")]
+    /// Never remove the generation with this number from any profile. Can be repeated
+    pub keep_generation: Vec<u32>,
+
+    #[arg(long, value_name = "GLOB", value_parser = glob::Pattern::new)]
+    /// Leave alone the profiles whose path matches this glob, like '*/ci-*'. Can be repeated
+    pub exclude: Vec<glob::Pattern>,
+
+    /// Only print actions and the space they would free, without performing them
+    #[arg(long, short = 'n', visible_alias = "dry-run")]
     pub dry: bool,
 
     /// Ask for confimation
@@ -230,6 +956,33 @@ pub struct CleanArgs {
     /// Don't clean gcroots
     #[arg(long)]
     pub nogcroots: bool,
+
+    /// Also remove the result symlinks into the store found under these directories, or the home
+    /// of the user running nh, even through sudo, when none are given. Can be repeated
+    #[arg(long, value_name = "DIR", num_args = 0..)]
+    pub remove_result_links: Option<Vec<PathBuf>>,
+
+    /// Also remove the out-link directories that interrupted rebuilds left in the temporary
+    /// directory, once they are an hour old and no rebuild uses them
+    #[arg(long)]
+    pub tmp: bool,
+
+    /// Number of threads scanning the profiles, defaults to the number of CPUs
+    #[arg(long, short, value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: Option<u32>,
+
+    /// Deduplicate the store with nix store optimise after the garbage collection
+    #[arg(long)]
+    pub optimise: bool,
+
+    /// Stop the garbage collection once it freed this much space, like 10GiB
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_freed: Option<u64>,
+
+    /// Only collect garbage until the store has this much free space, and not at all when it
+    /// already has it
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_free: Option<u64>,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -273,13 +1026,22 @@ pub struct HomeRebuildArgs {
     pub common: CommonRebuildArgs,
 
     /// Flake reference to build
-    #[arg(env = "FLAKE", value_hint = clap::ValueHint::DirPath, default_value = "~/.config/home-manager")]
+    #[arg(value_hint = clap::ValueHint::DirPath, default_value = default_flake("~/.config/home-manager"))]
     pub flakeref: FlakeRef,
 
     /// Name of the flake homeConfigurations attribute, like username@hostname
     #[arg(long, short)]
     pub configuration: Option<String>,
 
+    /// Rebuild every configuration in homeConfigurations, one after the other
+    #[arg(long, conflicts_with = "configuration")]
+    pub all: bool,
+
+    /// Build the home configuration of the user from the system configuration of this host, for
+    /// home-manager used as a NixOS or nix-darwin module
+    #[arg(long, conflicts_with_all = ["configuration", "all"])]
+    pub via_system: bool,
+
     /// Extra arguments passed to nix build
     #[arg(last = true)]
     pub extra_args: Vec<String>,
@@ -290,9 +1052,173 @@ pub struct HomeRebuildArgs {
 }
 
 #[derive(Debug, Parser)]
+#[command(group = clap::ArgGroup::new("shell_name").required(true))]
 /// Generate shell completion files into stdout
 pub struct CompletionArgs {
     /// Name of the shell
-    #[arg(long, short)]
-    pub shell: clap_complete::Shell,
+    #[arg(group = "shell_name")]
+    pub shell: Option<CompletionShell>,
+
+    /// Name of the shell, same as the positional argument
+    #[arg(long = "shell", short = 's', group = "shell_name", hide = true)]
+    pub shell_flag: Option<CompletionShell>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CompletionShell {
+    Bash,
+    Elvish,
+    Fish,
+    Nushell,
+    #[value(name = "powershell")]
+    PowerShell,
+    Zsh,
+}
+
+#[test]
+fn test_update_command() {
+    let parse = |args: &[&str]| {
+        let parser = NHParser::try_parse_from(["nh", "os", "switch"].iter().chain(args)).unwrap();
+        match parser.command {
+            NHCommand::Os(OsArgs {
+                action: OsRebuildType::Switch(args),
+            }) => args.common,
+            _ => unreachable!(),
+        }
+    };
+
+    let all = parse(&["--update"]);
+    assert_eq!(
+        all.update_command("/etc/nixos", Some("2.18.1")),
+        ["nix", "flake", "update", "/etc/nixos"]
+    );
+    assert_eq!(
+        all.update_command("/etc/nixos", Some("2.24.0")),
+        ["nix", "flake", "update", "--flake", "/etc/nixos"]
+    );
+    assert_eq!(
+        all.update_command("/etc/nixos", None),
+        ["nix", "flake", "update", "--flake", "/etc/nixos"]
+    );
+
+    let some = parse(&[
+        "--update-input",
+        "nixpkgs",
+        "--update-input",
+        "home-manager",
+    ]);
+    assert!(some.updates_flake());
+    assert_eq!(
+        some.update_command("/etc/nixos", Some("2.18.1")),
+        [
+            "nix",
+            "flake",
+            "lock",
+            "/etc/nixos",
+            "--update-input",
+            "nixpkgs",
+            "--update-input",
+            "home-manager"
+        ]
+    );
+    assert_eq!(
+        some.update_command("/etc/nixos", Some("2.24.0")),
+        [
+            "nix",
+            "flake",
+            "update",
+            "nixpkgs",
+            "home-manager",
+            "--flake",
+            "/etc/nixos"
+        ]
+    );
+}
+
+#[test]
+fn test_notify_env() {
+    assert!(notify_env(Some("1")));
+    assert!(notify_env(Some("true")));
+    assert!(notify_env(Some("Yes")));
+    assert!(!notify_env(Some("0")));
+    assert!(!notify_env(Some("no")));
+    assert!(!notify_env(Some("")));
+    assert!(!notify_env(None));
+}
+
+#[test]
+fn test_required_tools() {
+    let parse = |args: &[&str]| {
+        NHParser::try_parse_from(["nh"].iter().chain(args))
+            .unwrap()
+            .command
+    };
+
+    assert_eq!(
+        parse(&["os", "switch", "--no-nom"]).required_tools(),
+        ["nix", "nix-env", "nvd"]
+    );
+    assert_eq!(
+        parse(&["os", "boot", "--force-nom", "-D", "nix"]).required_tools(),
+        ["nix", "nix-env", "nom"]
+    );
+    assert_eq!(
+        parse(&["home", "build", "--no-nom", "-D", "my-diff --short"]).required_tools(),
+        ["my-diff", "nix"]
+    );
+    assert_eq!(
+        parse(&["os", "switch", "--no-nom", "--target-host", "host"]).required_tools(),
+        ["nix"]
+    );
+    assert_eq!(
+        parse(&["os", "switch", "--builder", "nixos-rebuild"]).required_tools(),
+        ["nix", crate::nixos::REBUILD_PROGRAM]
+    );
+    assert!(parse(&["completions", "--shell", "bash"])
+        .required_tools()
+        .is_empty());
+}
+
+#[test]
+fn test_keep_generations() {
+    let parse =
+        |keep: &str| NHParser::try_parse_from(["nh", "os", "switch", "--keep-generations", keep]);
+    assert!(parse("1").is_err());
+    assert!(parse("2").is_ok());
+}
+
+#[test]
+fn test_flakeref_local_path() {
+    let local_path = |flakeref: &str| FlakeRef::from(flakeref).local_path();
+
+    assert_eq!(local_path("/etc/nixos"), Some(PathBuf::from("/etc/nixos")));
+    assert_eq!(
+        local_path("./config#laptop"),
+        Some(PathBuf::from("./config"))
+    );
+    assert_eq!(
+        local_path("path:/etc/nixos"),
+        Some(PathBuf::from("/etc/nixos"))
+    );
+    assert_eq!(
+        local_path("git+file:///home/me/dotfiles?dir=nixos&ref=main#laptop"),
+        Some(PathBuf::from("/home/me/dotfiles/nixos"))
+    );
+    assert_eq!(
+        local_path("/home/me/dotfiles?dir=nixos"),
+        Some(PathBuf::from("/home/me/dotfiles/nixos"))
+    );
+    assert_eq!(local_path("github:me/dotfiles?dir=nixos"), None);
+    assert_eq!(local_path("git+https://example.com/dotfiles"), None);
+    assert_eq!(local_path("nixpkgs"), None);
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("1024"), Ok(1024));
+    assert_eq!(parse_size("5GiB"), Ok(5 << 30));
+    assert_eq!(parse_size("500M"), Ok(500 << 20));
+    assert_eq!(parse_size("1.5 G"), Ok(3 << 29));
+    assert!(parse_size("5 parsecs").is_err());
+    assert!(parse_size("GiB").is_err());
 }
@@ -1,10 +1,12 @@
 use crate::*;
+use color_eyre::eyre::Context;
 use owo_colors::OwoColorize;
 use tracing::Event;
 use tracing::Level;
 use tracing::Subscriber;
 use tracing_subscriber::filter::filter_fn;
 use tracing_subscriber::filter::FilterExt;
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt;
 
 use tracing_subscriber::fmt::FormatEvent;
@@ -31,7 +33,10 @@ where
         let metadata = event.metadata();
         let level = metadata.level();
 
-        if *level == Level::ERROR {
+        if !writer.has_ansi_escapes() {
+            let symbol = if *level == Level::INFO { ">" } else { "!" };
+            write!(writer, "{} ", symbol)?;
+        } else if *level == Level::ERROR {
             write!(writer, "{} ", "!".red())?;
         } else if *level == Level::WARN {
             write!(writer, "{} ", "!".yellow())?;
@@ -52,35 +57,82 @@ where
     }
 }
 
-pub(crate) fn setup_logging(verbose: bool) -> Result<()> {
+pub(crate) fn setup_logging(args: &interface::GlobalArgs) -> Result<()> {
+    let verbose = args.verbose > 0;
+    let quiet = args.quiet;
+    let color = args.use_color();
+    let human = args.log_format == interface::LogFormat::Human;
+
     color_eyre::config::HookBuilder::default()
+        .theme(match color {
+            true => color_eyre::config::Theme::dark(),
+            false => color_eyre::config::Theme::new(),
+        })
         .display_location_section(true)
         .panic_section("Please report the bug at https://github.com/ToyVo/nh_darwin/issues")
         .display_env_section(false)
         .install()?;
 
-    let layer_debug = fmt::layer()
-        .with_writer(std::io::stderr)
-        .without_time()
-        .compact()
-        .with_line_number(true)
-        .with_filter(EnvFilter::from_default_env().or(filter_fn(move |_| verbose)))
-        .with_filter(filter_fn(|meta| *meta.level() > Level::INFO));
-
-    let layer_info = fmt::layer()
-        .with_writer(std::io::stderr)
-        .without_time()
-        .with_target(false)
-        .with_level(false)
-        .event_format(InfoFormatter)
-        .with_filter(filter_fn(|meta| {
-            let level = *meta.level();
-            (level == Level::INFO) || (level == Level::WARN)
-        }));
+    let layer_debug = human.then(|| {
+        fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(color)
+            .without_time()
+            .compact()
+            .with_line_number(true)
+            .with_filter(EnvFilter::from_default_env().or(filter_fn(move |_| verbose)))
+            .with_filter(filter_fn(|meta| *meta.level() > Level::INFO))
+    });
+
+    let layer_info = human.then(|| {
+        fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(color)
+            .without_time()
+            .with_target(false)
+            .with_level(false)
+            .event_format(InfoFormatter)
+            .with_filter(filter_fn(move |meta| match *meta.level() {
+                Level::INFO => !quiet,
+                Level::WARN | Level::ERROR => true,
+                _ => false,
+            }))
+    });
+
+    let layer_json = (!human).then(|| {
+        fmt::layer()
+            .json()
+            .with_writer(std::io::stderr)
+            .with_line_number(true)
+            .with_filter(filter_fn(move |meta| match *meta.level() {
+                Level::INFO => !quiet,
+                Level::WARN | Level::ERROR => true,
+                _ => verbose,
+            }))
+    });
+
+    let layer_file = match &args.log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .wrap_err_with(|| format!("Opening the log file {path:?}"))?;
+            let layer = fmt::layer()
+                .with_writer(std::sync::Mutex::new(file))
+                .with_ansi(false)
+                .with_line_number(true)
+                .with_filter(LevelFilter::DEBUG);
+            Some(layer)
+        }
+        None => None,
+    };
 
     tracing_subscriber::registry()
         .with(layer_debug)
         .with(layer_info)
+        .with(layer_json)
+        .with(layer_file)
         .init();
 
     tracing::trace!("Logging OK");
@@ -1,6 +1,8 @@
 mod clean;
 mod commands;
 mod completion;
+mod config;
+mod history;
 mod home;
 mod interface;
 mod logging;
@@ -17,12 +19,71 @@ use std::ffi::OsString;
 
 const NH_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Phase of a rebuild that failed, exiting with its own code so that scripts can tell them apart.
+/// Usage errors exit with 2, from clap, and any other error with 1
+#[derive(Debug, thiserror::Error)]
+pub enum PhaseError {
+    #[error("The build failed")]
+    Build,
+    #[error("Comparing the changes failed")]
+    Diff,
+    #[error("The activation failed")]
+    Activation,
+    #[error("The confirmation was declined")]
+    Declined,
+}
+
+impl PhaseError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            PhaseError::Build => 3,
+            PhaseError::Diff => 4,
+            PhaseError::Activation => 5,
+            PhaseError::Declined => 6,
+        }
+    }
+}
+
 fn main() -> Result<()> {
-    let args = <NHParser as clap::Parser>::parse();
-    crate::logging::setup_logging(args.verbose)?;
+    // The config file provides the defaults, so it has to be read before parsing the arguments
+    let config = crate::config::Config::load()?;
+    let matches = config
+        .apply(<NHParser as clap::CommandFactory>::command())
+        .get_matches();
+    let args = <NHParser as clap::FromArgMatches>::from_arg_matches(&matches)
+        .unwrap_or_else(|err| err.exit());
+
+    // Child processes like nix only know about NO_COLOR
+    match args.global.color {
+        interface::ColorMode::Auto => {}
+        interface::ColorMode::Always => std::env::remove_var("NO_COLOR"),
+        interface::ColorMode::Never => std::env::set_var("NO_COLOR", "1"),
+    }
+
+    crate::logging::setup_logging(&args.global)?;
+    tracing::debug!(?config);
     tracing::debug!(?args);
 
-    args.command.run()
+    args.global.clone().install();
+    crate::commands::handle_interrupts()?;
+    crate::util::ensure_tools(&args.command.required_tools())?;
+    let result = args.command.run();
+
+    if let Err(err) = &result {
+        // Everything was cleaned up while unwinding, the error is only about being interrupted
+        if crate::commands::interrupted() {
+            std::process::exit(130);
+        }
+        if let Some(phase) = err.downcast_ref::<PhaseError>() {
+            // Declining is the user's choice, not something to report
+            if !matches!(phase, PhaseError::Declined) {
+                eprintln!("Error: {err:?}");
+            }
+            std::process::exit(phase.exit_code());
+        }
+    }
+
+    result
 }
 
 fn self_elevate() -> ! {
@@ -1,27 +1,69 @@
+use std::ffi::OsString;
 use std::fs;
+use std::io::IsTerminal;
 use std::ops::Deref;
 use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use color_eyre::eyre::{bail, Context};
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat};
 use color_eyre::Result;
 
-use tracing::{debug, info};
+use regex::Regex;
+use serde::Serialize;
+use tracing::{debug, info, warn};
 
 use crate::interface::NHRunnable;
-use crate::interface::OsRebuildType::{self, Boot, Build, Switch, Test};
-use crate::interface::{self, OsRebuildArgs};
-use crate::util::{compare_semver, get_nix_version};
+use crate::interface::OsRebuildType::{
+    self, Boot, Build, BuildVm, Diff, DryActivate, History, ListGenerations, Repl, Rollback,
+    Switch, Test,
+};
+use crate::interface::{
+    self, Builder, DiffAgainst, GlobalArgs, OsDiffArgs, OsHistoryArgs, OsListGenerationsArgs,
+    OsRebuildArgs, OsReplArgs, OsRollbackArgs, OutputFormat,
+};
+use crate::util::{get_nix_version, hostname, send_notification, RebuildSummary};
 use crate::*;
 
 const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
-const CURRENT_PROFILE: &str = "/run/current-system";
+const SYSTEM_PROFILES_DIR: &str = "/nix/var/nix/profiles/system-profiles";
+pub const CURRENT_PROFILE: &str = "/run/current-system";
+const BOOTED_PROFILE: &str = "/run/booted-system";
 
 const SPEC_LOCATION: &str = "/etc/specialisation";
 
+#[cfg(target_os = "linux")]
+pub const CONFIGURATION_MODULE: &str = "nixosConfigurations";
+#[cfg(target_os = "macos")]
+pub const CONFIGURATION_MODULE: &str = "darwinConfigurations";
+
+#[cfg(target_os = "linux")]
+pub const REBUILD_PROGRAM: &str = "nixos-rebuild";
+#[cfg(target_os = "macos")]
+pub const REBUILD_PROGRAM: &str = "darwin-rebuild";
+
+#[cfg(target_os = "linux")]
+const CHANNEL_ENTRYPOINT: &str = "<nixpkgs/nixos>";
+#[cfg(target_os = "macos")]
+const CHANNEL_ENTRYPOINT: &str = "<darwin>";
+
+#[cfg(target_os = "linux")]
+const CHANNEL_CONFIG: (&str, &str) = ("nixos-config", "configuration.nix");
+#[cfg(target_os = "macos")]
+const CHANNEL_CONFIG: (&str, &str) = ("darwin-config", "darwin-configuration.nix");
+
 impl NHRunnable for interface::OsArgs {
     fn run(&self) -> Result<()> {
         match &self.action {
-            Switch(args) | Boot(args) | Test(args) | Build(args) => args.rebuild(&self.action),
+            Switch(args) | Boot(args) | Test(args) | Build(args) | DryActivate(args) => {
+                args.rebuild(&self.action)
+            }
+            BuildVm(args) => args.common.rebuild(&self.action),
+            Rollback(args) => args.rollback(),
+            ListGenerations(args) => args.list(),
+            Diff(args) => args.diff(),
+            Repl(args) => args.repl(),
+            History(args) => args.history(),
             s => bail!("Subcommand {:?} not yet implemented", s),
         }
     }
@@ -29,234 +71,1677 @@ impl NHRunnable for interface::OsArgs {
 
 impl OsRebuildArgs {
     pub fn rebuild(&self, rebuild_type: &OsRebuildType) -> Result<()> {
-        let effective_uid = nix::unistd::Uid::effective();
-        let effective_gid = nix::unistd::Gid::effective();
+        if self.hostname.len() > 1 {
+            if !matches!(rebuild_type, Build(_)) {
+                bail!("Only build accepts several hostnames");
+            }
+            return self.build_hosts(rebuild_type);
+        }
 
-        let hostname = match &self.hostname {
-            Some(h) => h.to_owned(),
-            None => hostname::get().context("Failed to get hostname")?,
+        let start = Instant::now();
+        let mut timings = Timings::default();
+
+        let json = self.common.output_format == OutputFormat::Json;
+        if json {
+            commands::keep_stdout_clean();
+        }
+        let mut summary = RebuildSummary {
+            action: action_name(rebuild_type).to_owned(),
+            flakeref: self.flakeref.deref().to_owned(),
+            ..Default::default()
         };
 
         let out_dir = tempfile::Builder::new().prefix("nh-os-").tempdir()?;
-        let out_link = out_dir.path().join("result");
-        let out_link_str = out_link.to_str().unwrap();
+        let _lock = util::try_lock_dir(out_dir.path(), false)?;
         debug!("out_dir: {:?}", out_dir);
-        debug!("out_link {:?}", out_link);
+        let result = self.rebuild_inner(rebuild_type, out_dir.path(), &mut timings, &mut summary);
 
-        // check if flake is owned by root
-        let flake_is_owned_by_root = match fs::metadata(&self.flakeref) {
-            Ok(metadata) => nix::unistd::Uid::from_raw(metadata.uid()).is_root(),
-            // flakeref is not found on system or user does not have permissions to get metadata
-            // so we assume it is not owned by root
-            // (could be a flake from github or the registry)
-            Err(_) => false, 
+        // Drop the out dir *only* when we are finished, unless the result is kept for debugging
+        let out_link = out_dir.path().join("result");
+        if result.is_err() && self.keep_result_on_failure && out_link.is_symlink() {
+            let kept = out_dir.into_path().join("result");
+            warn!(
+                "Kept the result of the failed rebuild at {}",
+                kept.display()
+            );
+        } else {
+            drop(out_dir);
+        }
+
+        if self.timings && !timings.0.is_empty() {
+            info!("Timings: {timings}");
+        }
+
+        // Only notify interactive runs, where someone may have walked away from the terminal
+        if self.common.notify && std::io::stdout().is_terminal() {
+            let hostname = self
+                .hostname
+                .first()
+                .cloned()
+                .or_else(|| hostname().ok().map(OsString::from))
+                .unwrap_or_default();
+            let elapsed =
+                humantime::format_duration(Duration::from_secs(start.elapsed().as_secs()));
+            let summary = match result {
+                Ok(_) => "Rebuild finished",
+                Err(_) => "Rebuild failed",
+            };
+            send_notification(
+                summary,
+                &format!("{} after {elapsed}", hostname.to_string_lossy()),
+            );
+        }
+
+        if json && result.is_ok() {
+            summary.print(start)?;
+        }
+
+        result
+    }
+
+    /// Builds and activates the configuration through nixos-rebuild or darwin-rebuild
+    fn rebuild_upstream(
+        &self,
+        rebuild_type: &OsRebuildType,
+        hostname: &OsString,
+        is_flake: bool,
+        channel_args: &[String],
+    ) -> Result<()> {
+        let program = REBUILD_PROGRAM;
+
+        let action = match rebuild_type {
+            _ if self.common.dry => "dry-build",
+            Switch(_) | Boot(_) | Test(_) | Build(_) | DryActivate(_) | BuildVm(_) => {
+                action_name(rebuild_type)
+            }
+            _ => bail!("{program} can't run {rebuild_type:?}"),
         };
-        debug!("flakeref is owned by root: {:?}", flake_is_owned_by_root);
+        let activates = !matches!(action, "dry-build" | "build" | "build-vm");
 
-        // if we are root, then we do not need to elevate
-        // if we are not root, and the flake is owned by root, then we need to elevate
-        let elevation_required = !effective_uid.is_root() && flake_is_owned_by_root;
+        // The invalid command lines are refused before asking for confirmation
+        if self.system.is_some() {
+            bail!("--system can't be used with --builder nixos-rebuild");
+        }
+        if is_flake && self.configuration_attr.is_some() {
+            bail!("--configuration-attr can't be used with --builder nixos-rebuild");
+        }
 
-        if self.common.pull {
-            commands::CommandBuilder::default()
-                .root(elevation_required)
-                .args(["git", "-C", &self.flakeref, "pull"])
-                .message("Pulling flake")
-                .build()?
-                .exec()?;
+        if activates && self.common.ask && !util::confirm(&format!("Run {program} {action}?"))? {
+            bail!(PhaseError::Declined);
+        }
+
+        let mut cmd = commands::CommandBuilder::default();
+        cmd.args([program, action]);
+
+        if is_flake {
+            let flake = format!("{}#{}", self.flakeref.deref(), hostname.to_string_lossy());
+            cmd.args(["--flake".to_owned(), flake]);
+        } else {
+            cmd.args(channel_args);
         }
 
+        if let Some(spec) = &self.specialisation {
+            cmd.args(["--specialisation", spec]);
+        }
+        if let Some(name) = &self.profile_name {
+            cmd.args(["--profile-name", name]);
+        }
         #[cfg(target_os = "linux")]
-        let configuration_module = "nixosConfigurations";
-        #[cfg(target_os = "macos")]
-        let configuration_module = "darwinConfigurations";
+        if self.install_bootloader {
+            cmd.args(["--install-bootloader"]);
+        }
+        if let Some(host) = &self.build_host {
+            cmd.args(["--build-host", host]);
+        }
+        if let Some(host) = &self.target_host {
+            cmd.args(["--target-host", host, "--use-remote-sudo"]);
+        }
 
-        let flake_output = format!(
-            "{}#{configuration_module}.{hostname:?}.config.system.build.toplevel",
-            &self.flakeref.deref()
-        );
+        // Activating the local system needs root, remote ones go through --use-remote-sudo
+        let elevate =
+            activates && self.target_host.is_none() && !nix::unistd::Uid::effective().is_root();
 
-        if self.common.update {
-            // Get the Nix version
-            let nix_version = get_nix_version().unwrap_or_else(|_| {
-                panic!("Failed to get Nix version. Custom Nix fork?");
-            });
+        cmd.args(self.common.build_args())
+            .args(&self.extra_args)
+            .root(elevate)
+            .message(format!("Running {program} {action}"))
+            .build()?
+            .exec()
+            .wrap_err(match activates {
+                true => PhaseError::Activation,
+                false => PhaseError::Build,
+            })
+    }
 
-            let status = commands::CommandBuilder::default()
-                .args(["git", "-C", &self.flakeref, "diff", "--name-only", "--diff-filter=U"])
-                .message("Checking for conflicts")
-                .build()?
-                .exec_capture()?;
+    /// Number of the generation the profile points to, on the target host if there's one
+    fn profile_generation(&self, profile: &Path) -> Option<u32> {
+        let link = match &self.target_host {
+            Some(host) => commands::CommandBuilder::default()
+                .ssh(Some(host.clone()))
+                .args(["readlink"])
+                .args([profile])
+                .build()
+                .ok()?
+                .exec_capture()
+                .ok()??
+                .trim()
+                .to_owned(),
+            None => profile.read_link().ok()?.to_string_lossy().into_owned(),
+        };
+        link_generation(&link).ok()
+    }
 
-            if let Some(conflict) = status {
-                if conflict == "flake.lock\n".to_string() {
-                    commands::CommandBuilder::default()
-                        .args(["git", "-C", &self.flakeref, "reset", "flake.lock"])
-                        .message("Resetting flake.lock")
-                        .build()?
-                        .exec()?;
+    /// Resolves the configuration the target system runs right now
+    fn current_system(&self) -> Result<PathBuf> {
+        match &self.target_host {
+            Some(host) => {
+                let path = commands::CommandBuilder::default()
+                    .ssh(Some(host.clone()))
+                    .args(["readlink", "-f", CURRENT_PROFILE])
+                    .build()?
+                    .exec_capture()?
+                    .unwrap_or_default();
+                if path.trim().is_empty() {
+                    bail!("Couldn't resolve {CURRENT_PROFILE} on {host}");
+                }
+                Ok(PathBuf::from(path.trim()))
+            }
+            None => fs::canonicalize(CURRENT_PROFILE)
+                .with_context(|| format!("Resolving {CURRENT_PROFILE}")),
+        }
+    }
+
+    /// Puts the profile back and activates the previous system after a failed activation. Its own
+    /// failures are only reported, so that the original error is the one returned
+    fn roll_back(
+        &self,
+        rebuild_type: &OsRebuildType,
+        previous: &Path,
+        profile: Option<(PathBuf, bool, Option<u32>)>,
+        elevate: bool,
+    ) {
+        warn!("Activation failed, rolling back to {}", previous.display());
+        let result = (|| -> Result<()> {
+            // The profile goes back to the generation it pointed to, which isn't always the one
+            // below the new generation
+            match profile {
+                Some((profile, can_write, Some(generation))) => {
                     commands::CommandBuilder::default()
-                        .args(["git", "-C", &self.flakeref, "checkout", "flake.lock"])
-                        .message("Checking out flake.lock")
+                        .ssh(self.target_host.clone())
+                        .root(elevate && !can_write)
+                        .args(["nix-env", "--profile"])
+                        .args([&profile])
+                        .args(["--switch-generation".to_owned(), generation.to_string()])
+                        .message(format!(
+                            "Rolling back the profile to generation {generation}"
+                        ))
                         .build()?
                         .exec()?;
-                } else if conflict != "" {
-                    panic!("Conflicts dectected that were more than just flake.lock, {conflict:?}");
                 }
+                Some((profile, _, None)) => {
+                    warn!(
+                        "{} had no generation before, leaving it as is",
+                        profile.display()
+                    );
+                }
+                None => {}
             }
 
-            // Default interface for updating flake inputs
-            let mut update_args = vec!["nix", "flake", "update"];
+            #[cfg(target_os = "linux")]
+            {
+                let action = match rebuild_type {
+                    Boot(_) => "boot",
+                    Test(_) => "test",
+                    _ => "switch",
+                };
+                commands::CommandBuilder::default()
+                    .ssh(self.target_host.clone())
+                    .root(elevate)
+                    .args([previous.join("bin").join("switch-to-configuration")])
+                    .args([action])
+                    .message("Activating the previous configuration")
+                    .build()?
+                    .exec()?;
+            }
 
-            // If user is on Nix 2.19.0 or above, --flake must be passed
-            if let Ok(ordering) = compare_semver(&nix_version, "2.19.0") {
-                if ordering == std::cmp::Ordering::Greater {
-                    update_args.push("--flake");
-                }
+            #[cfg(target_os = "macos")]
+            {
+                let _ = rebuild_type;
+                commands::CommandBuilder::default()
+                    .ssh(self.target_host.clone())
+                    .args([previous.join("activate-user")])
+                    .message("Activating the previous configuration for user")
+                    .build()?
+                    .exec()?;
+                commands::CommandBuilder::default()
+                    .ssh(self.target_host.clone())
+                    .root(elevate)
+                    .args([previous.join("activate")])
+                    .message("Activating the previous configuration")
+                    .build()?
+                    .exec()?;
+            }
+
+            Ok(())
+        })();
+
+        if let Err(err) = result {
+            warn!("Rolling back failed: {err:#}");
+        }
+    }
+
+    /// Builds the configuration of every hostname, printing their store paths, which contain the
+    /// hostname
+    fn build_hosts(&self, rebuild_type: &OsRebuildType) -> Result<()> {
+        // The hosts share the flake, so it's pulled, staged, checked and updated once instead of
+        // racing on it
+        let source = self.flake_source();
+        self.pull(&source)?;
+        if let Some(git_dir) = source.git_dir.as_ref().filter(|_| source.is_flake) {
+            if self.common.stage_untracked {
+                util::stage_untracked(git_dir, source.elevation_required)?;
+            }
+            util::check_git_tree(git_dir, false, self.common.stage_untracked)?;
+        }
+        let updated_inputs = self.update(&source)?;
+
+        let jobs = self.jobs.unwrap_or(1) as usize;
+        let results = util::parallel_map(&self.hostname, jobs, |host| {
+            let mut args = self.clone();
+            args.hostname = vec![host.clone()];
+            args.common.print_out_path = true;
+            args.common.notify = false;
+            args.common.pull = false;
+            args.common.stage_untracked = false;
+            args.skip_git_checks = true;
+            args.common.update = false;
+            args.common.update_input.clear();
+            args.commit_lock = false;
+            // Several nom UIs can't share the terminal
+            if jobs > 1 {
+                args.common.no_nom = true;
+                args.common.force_nom = false;
+            }
+            args.rebuild(rebuild_type)
+        });
+
+        let mut failed = Vec::new();
+        for (host, result) in self.hostname.iter().zip(results) {
+            if let Err(err) = result {
+                warn!("Building {} failed: {err:#}", host.to_string_lossy());
+                failed.push(host.to_string_lossy());
             }
+        }
 
-            update_args.push(&self.flakeref);
+        if !failed.is_empty() {
+            return Err(eyre!("Couldn't build {}", failed.join(", "))).wrap_err(PhaseError::Build);
+        }
+
+        info!("Built {} configurations", self.hostname.len());
+        self.commit_lock_file(&source, &updated_inputs)
+    }
+
+    /// Finds where the configuration comes from, and whether changing its directory needs root
+    fn flake_source(&self) -> FlakeSource {
+        // Git and the ownership check work on the directory of local flakes
+        let local_flake = self.flakeref.local_path();
+        debug!("local_flake: {:?}", local_flake);
+
+        // check if flake is owned by root
+        let metadata = local_flake.as_ref().map(fs::metadata);
+        let flake_is_owned_by_root = match metadata {
+            Some(Ok(metadata)) => nix::unistd::Uid::from_raw(metadata.uid()).is_root(),
+            // flakeref is not found on system or user does not have permissions to get metadata
+            // so we assume it is not owned by root
+            // (could be a flake from github or the registry)
+            _ => false,
+        };
+        debug!("flakeref is owned by root: {:?}", flake_is_owned_by_root);
+
+        // if we are root, then we do not need to elevate
+        // if we are not root, and the flake is owned by root, then we need to elevate
+        let elevation_required = !nix::unistd::Uid::effective().is_root() && flake_is_owned_by_root;
+
+        // A local directory without a flake.nix is treated as a channels-based configuration
+        let flake_path = local_flake
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(self.flakeref.as_str()));
+        let has_flake_nix = !flake_path.is_dir() || flake_path.join("flake.nix").exists();
+        let is_flake = !self.no_flake && has_flake_nix;
+        debug!("flakeref is a flake: {is_flake}");
+
+        FlakeSource {
+            git_dir: local_flake.map(|dir| dir.to_string_lossy().into_owned()),
+            flake_path,
+            is_flake,
+            elevation_required,
+        }
+    }
 
-            debug!("nix_version: {:?}", nix_version);
-            debug!("update_args: {:?}", update_args);
+    /// Pulls the git repository of a local flake with --pull
+    fn pull(&self, source: &FlakeSource) -> Result<()> {
+        if !self.common.pull {
+            return Ok(());
+        }
+        match &source.git_dir {
+            Some(git_dir) => commands::CommandBuilder::default()
+                .root(source.elevation_required)
+                .args(["git", "-C", git_dir, "pull"])
+                .message("Pulling flake")
+                .interactive(true)
+                .build()?
+                .exec(),
+            None => {
+                warn!(
+                    "Not pulling {}, which isn't a local flake",
+                    self.flakeref.deref()
+                );
+                Ok(())
+            }
+        }
+    }
 
+    /// Updates the channels or the flake inputs when asked, returning the inputs that changed
+    fn update(&self, source: &FlakeSource) -> Result<Vec<String>> {
+        if self.common.update && !source.is_flake {
             commands::CommandBuilder::default()
-                .root(elevation_required)
-                .args(&update_args)
-                .message("Updating flake")
+                .root(!nix::unistd::Uid::effective().is_root())
+                .args(["nix-channel", "--update"])
+                .message("Updating channels")
                 .build()?
                 .exec()?;
         }
 
-        #[cfg(target_os = "linux")]
-        let message = "Building NixOS configuration";
-        #[cfg(target_os = "macos")]
-        let message = "Building Darwin configuration";
+        if !self.common.updates_flake() || !source.is_flake {
+            return Ok(Vec::new());
+        }
 
-        commands::BuildCommandBuilder::default()
-            .flakeref(flake_output)
-            .message(message)
-            .extra_args(["--out-link", out_link_str])
-            .extra_args(&self.extra_args)
-            .nom(!self.common.no_nom)
-            .build()?
-            .exec()?;
+        let lock_path = source.flake_path.join("flake.lock");
+        let lock_before = read_lock_file(&lock_path);
 
-        let current_specialisation = std::fs::read_to_string(SPEC_LOCATION).ok();
+        // Get the Nix version
+        let nix_version = get_nix_version()
+            .map_err(|err| warn!("Couldn't detect the Nix version, assuming a recent one: {err}"))
+            .ok();
 
-        let target_specialisation = if self.no_specialisation {
-            None
-        } else {
-            current_specialisation.or_else(|| self.specialisation.to_owned())
+        let output = match &source.git_dir {
+            Some(git_dir) => commands::CommandBuilder::default()
+                .args([
+                    "git",
+                    "-C",
+                    git_dir,
+                    "diff",
+                    "--name-only",
+                    "--diff-filter=U",
+                ])
+                .message("Checking for conflicts")
+                .build()?
+                .exec_capture_split()?,
+            None => None,
         };
 
-        debug!("target_specialisation: {target_specialisation:?}");
+        match output {
+            Some(output) if !output.status.success() => {
+                debug!("Not checking for conflicts: {}", output.stderr.trim());
+            }
+            Some(output) if output.stdout == "flake.lock\n" => {
+                // Only local flakes are checked for conflicts
+                let git_dir = source.git_dir.as_deref().unwrap_or_default();
+                commands::CommandBuilder::default()
+                    .args(["git", "-C", git_dir, "reset", "flake.lock"])
+                    .message("Resetting flake.lock")
+                    .build()?
+                    .exec()?;
+                commands::CommandBuilder::default()
+                    .args(["git", "-C", git_dir, "checkout", "flake.lock"])
+                    .message("Checking out flake.lock")
+                    .build()?
+                    .exec()?;
+            }
+            Some(output) if !output.stdout.is_empty() => {
+                panic!(
+                    "Conflicts dectected that were more than just flake.lock, {:?}",
+                    output.stdout
+                );
+            }
+            _ => {}
+        }
 
-        let target_profile = match &target_specialisation {
-            None => out_link.to_owned(),
-            Some(spec) => out_link.join("specialisation").join(spec),
-        };
+        let update_args = self
+            .common
+            .update_command(&self.flakeref, nix_version.as_deref());
 
-        target_profile.try_exists().context("Doesn't exist")?;
+        debug!("nix_version: {:?}", nix_version);
+        debug!("update_args: {:?}", update_args);
 
         commands::CommandBuilder::default()
-            .args(self.common.diff_provider.split_ascii_whitespace())
-            .args([
-                CURRENT_PROFILE,
-                target_profile.to_str().unwrap(),
-            ])
-            .message("Comparing changes")
+            .root(source.elevation_required)
+            .args(&update_args)
+            .args(self.common.impure.then_some("--impure"))
+            .args(
+                self.common
+                    .accept_flake_config
+                    .then_some("--accept-flake-config"),
+            )
+            .args(self.common.refresh.then_some("--refresh"))
+            .args(GlobalArgs::get().nix_verbosity())
+            .message("Updating flake")
             .build()?
             .exec()?;
 
-        if self.common.dry || matches!(rebuild_type, OsRebuildType::Build(_)) {
+        let updated_inputs = match (lock_before, read_lock_file(&lock_path)) {
+            (Some(before), Some(after)) => changed_lock_inputs(&before, &after),
+            _ => Vec::new(),
+        };
+        debug!("updated_inputs: {updated_inputs:?}");
+        Ok(updated_inputs)
+    }
+
+    /// Commits the updated flake.lock with --commit-lock. The lock file is only read from local
+    /// flakes, which have a directory to commit in
+    fn commit_lock_file(&self, source: &FlakeSource, updated_inputs: &[String]) -> Result<()> {
+        if !self.commit_lock || updated_inputs.is_empty() {
             return Ok(());
         }
 
-        if self.common.ask {
-            info!("Apply the config?");
-            let confirmation = dialoguer::Confirm::new().default(false).interact()?;
+        let git_dir = source.git_dir.as_deref().unwrap_or_default();
+        let mut commit_message = String::from("flake.lock: update\n\nUpdated inputs:\n");
+        for input in updated_inputs {
+            commit_message.push_str(&format!("- {input}\n"));
+        }
 
-            if !confirmation {
-                return Ok(());
+        commands::CommandBuilder::default()
+            .root(source.elevation_required)
+            .args(["git", "-C", git_dir, "commit", "flake.lock", "-m"])
+            .args([&commit_message])
+            .message("Committing flake.lock")
+            .build()?
+            .exec()
+    }
+
+    fn rebuild_inner(
+        &self,
+        rebuild_type: &OsRebuildType,
+        out_dir: &Path,
+        timings: &mut Timings,
+        summary: &mut RebuildSummary,
+    ) -> Result<()> {
+        let effective_uid = nix::unistd::Uid::effective();
+
+        let hostname = match self.hostname.first() {
+            Some(h) => h.to_owned(),
+            None => hostname().context("Failed to get hostname")?.into(),
+        };
+        summary.hostname = hostname.to_string_lossy().into_owned();
+
+        let out_link = out_dir.join("result");
+        let out_link_str = out_link.to_str().unwrap();
+        debug!("out_link {:?}", out_link);
+
+        let source = self.flake_source();
+        let FlakeSource {
+            flake_path,
+            git_dir,
+            is_flake,
+            elevation_required,
+        } = source.clone();
+
+        self.pull(&source)?;
+
+        if let Some(git_dir) = git_dir
+            .as_ref()
+            .filter(|_| is_flake && !self.skip_git_checks)
+        {
+            if self.common.stage_untracked {
+                util::stage_untracked(git_dir, elevation_required)?;
             }
+            // Only activations that persist need to come from a committed tree
+            let refuse = matches!(rebuild_type, Switch(_) | Boot(_)) && self.common.refuse_dirty;
+            util::check_git_tree(git_dir, refuse, self.common.stage_untracked)?;
         }
 
-        #[cfg(target_os = "linux")]
-        if let Test(_) | Switch(_) = rebuild_type {
-            // !! Use the target profile aka spec-namespaced
-            let switch_to_configuration =
-                target_profile.join("bin").join("switch-to-configuration");
-            let switch_to_configuration = switch_to_configuration.to_str().unwrap();
+        let configuration_module = CONFIGURATION_MODULE;
 
-            commands::CommandBuilder::default()
-                .root(!effective_uid.is_root())
-                .args([switch_to_configuration, "test"])
-                .message("Activating configuration")
-                .build()?
-                .exec()?;
+        #[cfg(target_os = "macos")]
+        match rebuild_type {
+            BuildVm(_) => bail!("nh os build-vm is only supported on NixOS"),
+            DryActivate(_) => bail!("nh os dry-activate is only supported on NixOS"),
+            _ => (),
         }
 
-        if let Boot(_) | Switch(_) = rebuild_type {
-            let profile_metadata =
-                fs::metadata(SYSTEM_PROFILE).context("Failed to get metadata of profile")?;
-            let profile_uid = nix::unistd::Uid::from_raw(profile_metadata.uid());
-            let profile_gid = nix::unistd::Gid::from_raw(profile_metadata.gid());
-            let can_write = !profile_metadata.permissions().readonly() && (effective_uid == profile_uid || effective_gid == profile_gid);
-            debug!("${SYSTEM_PROFILE} is writable by user: {can_write}");
+        #[cfg(target_os = "macos")]
+        if self.diff_against == DiffAgainst::Booted {
+            bail!("--diff-against booted is only supported on NixOS");
+        }
 
-            commands::CommandBuilder::default()
-                .root(!effective_uid.is_root() && !can_write)
-                .args([
-                    "nix-env",
-                    "--profile",
-                    SYSTEM_PROFILE,
-                    "--set",
-                    out_link_str,
-                ])
+        // A configuration for another system can't run here
+        if self.system.is_some() && self.target_host.is_none() && !matches!(rebuild_type, Build(_))
+        {
+            bail!("--system can only be activated on a matching --target-host, use nh os build otherwise");
+        }
+
+        let build_attr = match rebuild_type {
+            BuildVm(_) => "vm",
+            _ => "toplevel",
+        };
+
+        // Evaluating remote flakes may need to fetch them, so only local ones are checked. The
+        // flake is evaluated with the same arguments as the build, for the overridden inputs
+        let check_hostname = is_flake
+            && flake_path.exists()
+            && self.configuration_attr.is_none()
+            && !self.no_hostname_check;
+        if check_hostname {
+            let names = commands::CommandBuilder::default()
+                .args(["nix", "eval", "--json", "--apply", "builtins.attrNames"])
+                .args(commands::eval_args(
+                    self.common.build_args().iter().chain(&self.extra_args),
+                ))
+                .args([format!("{}#{configuration_module}", self.flakeref.deref())])
+                .message("Checking the configuration exists")
                 .build()?
-                .exec()?;
+                .exec_capture_split()?
+                .map(|output| {
+                    if !output.status.success() {
+                        bail!(
+                            "Failed to evaluate {configuration_module} from {}:\n{}",
+                            self.flakeref.deref(),
+                            output.stderr.trim()
+                        );
+                    }
+                    Ok(serde_json::from_str::<Vec<String>>(&output.stdout).ok())
+                })
+                .transpose()?
+                .flatten();
+            debug!("configurations: {names:?}");
 
-            // !! Use the base profile aka no spec-namespace
-            #[cfg(target_os = "linux")]
-            {
-                let switch_to_configuration = out_link.join("bin").join("switch-to-configuration");
-                let switch_to_configuration = switch_to_configuration.to_str().unwrap();
+            if let Some(names) = names {
+                let hostname = hostname.to_string_lossy();
+                if !names.iter().any(|name| *name == hostname) {
+                    bail!(
+                        "Configuration {hostname:?} doesn't exist in {configuration_module}, available configurations: {}",
+                        names.join(", ")
+                    );
+                }
+            }
+        }
 
-                commands::CommandBuilder::default()
-                    .root(!effective_uid.is_root())
-                    .args([switch_to_configuration, "boot"])
-                    .message("Adding configuration to bootloader")
-                    .build()?
-                    .exec()?;
+        let configuration_attr = match &self.configuration_attr {
+            Some(attr) => attr.to_owned(),
+            None => format!("{configuration_module}.{hostname:?}"),
+        };
+
+        let flake_output = if is_flake {
+            format!(
+                "{}#{configuration_attr}.config.system.build.{build_attr}",
+                &self.flakeref.deref()
+            )
+        } else {
+            format!("config.system.build.{build_attr}")
+        };
+
+        let update_start = Instant::now();
+
+        // Without a flake, the configuration is picked up from the channels in NIX_PATH
+        let mut channel_args = Vec::new();
+        if !is_flake {
+            let (config_var, config_file) = CHANNEL_CONFIG;
+            let config_path = flake_path.join(config_file);
+            if config_path.exists() {
+                channel_args.push("-I".to_owned());
+                channel_args.push(format!("{config_var}={}", config_path.display()));
             }
+        }
 
-            #[cfg(target_os = "macos")]
-            {
-                let activate_user = out_link.join("activate-user");
-                let activate_user = activate_user.to_str().unwrap();
+        let updated_inputs = self.update(&source)?;
 
-                commands::CommandBuilder::default()
-                    .args([activate_user])
-                    .message("Activating configuration for user")
-                    .build()?
-                    .exec()?;
+        if self.common.updates_flake() {
+            timings.record("update", update_start);
+        }
+
+        // A build host builds in its own store
+        if self.build_host.is_none() {
+            util::check_free_space(self.warn_free, self.min_free)?;
+        }
+
+        if self.builder == Builder::NixosRebuild {
+            return self.rebuild_upstream(rebuild_type, &hostname, is_flake, &channel_args);
+        }
+
+        #[cfg(target_os = "linux")]
+        let message = "Building NixOS configuration";
+        #[cfg(target_os = "macos")]
+        let message = "Building Darwin configuration";
+
+        let build_start = Instant::now();
+        commands::BuildCommandBuilder::default()
+            .flakeref(flake_output)
+            .file((!is_flake).then(|| CHANNEL_ENTRYPOINT.to_owned()))
+            .message(message)
+            .extra_args(["--out-link", out_link_str])
+            .extra_args(&channel_args)
+            .extra_args(self.common.build_args())
+            .extra_args(&self.extra_args)
+            .nom(self.common.use_nom())
+            .retries(self.common.retries)
+            .build_host(self.build_host.clone())
+            .system(self.system.clone())
+            .build()?
+            .exec()
+            .wrap_err(PhaseError::Build)?;
+        timings.record("build", build_start);
+
+        // Nothing was built with --dry-run-commands, so the out-link stands in for the store path
+        let dry_run_commands = GlobalArgs::get().dry_run_commands;
+        let read_out_link = || match dry_run_commands {
+            true => Ok(out_link.clone()),
+            false => fs::read_link(&out_link).context("Reading the out-link"),
+        };
+
+        if !dry_run_commands {
+            summary.store_path = Some(read_out_link()?.display().to_string());
+        }
+
+        // The summary already has the store path, and is the only output on stdout
+        if self.common.print_out_path && self.common.output_format == OutputFormat::Human {
+            println!("{}", read_out_link()?.display());
+        }
+
+        if let BuildVm(vm_args) = rebuild_type {
+            if dry_run_commands {
+                return Ok(());
+            }
 
-                let activate = out_link.join("activate");
-                let activate = activate.to_str().unwrap();
+            let run_script = out_link
+                .join("bin")
+                .read_dir()
+                .context("Reading the VM's bin directory")?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .find(|path| {
+                    let name = path.file_name().unwrap().to_string_lossy();
+                    name.starts_with("run-") && name.ends_with("-vm")
+                })
+                .context("Couldn't find the VM's run script")?;
+            // The out-link is removed with the tempdir, so point to the store instead
+            let run_script = fs::canonicalize(run_script)?;
 
+            println!("{}", run_script.display());
+
+            if vm_args.run {
                 commands::CommandBuilder::default()
-                    .root(!effective_uid.is_root())
-                    .args([activate])
-                    .message("Activating configuration")
+                    .args([&run_script])
+                    .message("Running the virtual machine")
+                    .interactive(true)
                     .build()?
                     .exec()?;
             }
+
+            return Ok(());
         }
 
-        // Drop the out dir *only* when we are finished
-        drop(out_dir);
+        // The out-link only exists locally, so remote commands need the store path itself
+        let toplevel = match &self.target_host {
+            Some(_) => read_out_link()?,
+            None => out_link.clone(),
+        };
+        let toplevel_str = toplevel.to_str().unwrap();
 
-        Ok(())
-    }
+        // Signing and copying to the target host leave the profiles alone, so they run in the
+        // background while the diff renders, and are waited for before anything is activated
+        let mut background = {
+            let sign = self
+                .sign_with
+                .clone()
+                .map(|key_file| (key_file, out_link.clone()));
+            let copy = self
+                .target_host
+                .clone()
+                .map(|host| (host, toplevel.clone()));
+            util::Task::spawn(move || {
+                if let Some((key_file, out_link)) = sign {
+                    commands::CommandBuilder::default()
+                        .args(["nix", "store", "sign", "--recursive", "--key-file"])
+                        .args([key_file, out_link])
+                        .message("Signing the configuration")
+                        .build()?
+                        .exec()?;
+                }
+                if let Some((host, store_path)) = copy {
+                    commands::CommandBuilder::default()
+                        .args(["nix", "copy", "--to", &format!("ssh://{host}")])
+                        .args([&store_path])
+                        .message(format!("Copying the configuration to {host}"))
+                        .build()?
+                        .exec()?;
+                }
+                Ok(())
+            })
+        };
+        // The printed commands stay in order
+        if dry_run_commands {
+            background.wait()?;
+        }
+
+        // The specialisation marker of a remote host can't be read from here
+        let current_specialisation = match &self.target_host {
+            Some(_) => None,
+            None => std::fs::read_to_string(SPEC_LOCATION)
+                .ok()
+                .map(|spec| spec.trim().to_owned()),
+        };
+
+        let target_specialisation = if self.no_specialisation {
+            None
+        } else {
+            current_specialisation.or_else(|| self.specialisation.to_owned())
+        };
+
+        debug!("target_specialisation: {target_specialisation:?}");
+
+        let target_profile = match &target_specialisation {
+            None => toplevel.to_owned(),
+            Some(spec) => toplevel.join("specialisation").join(spec),
+        };
+
+        // The result of a remote or skipped build can't be inspected from here
+        if self.target_host.is_none() && !dry_run_commands {
+            if let Some(spec) = &target_specialisation {
+                let available = specialisations(&toplevel);
+                if !available.contains(spec) {
+                    bail!(
+                        "Specialisation '{spec}' not found; available: [{}]",
+                        available.join(", ")
+                    );
+                }
+            }
+
+            if !target_profile.try_exists()? {
+                bail!("{} doesn't exist", target_profile.display());
+            }
+        }
+
+        let base_profile = match self.diff_against {
+            DiffAgainst::Current => CURRENT_PROFILE,
+            DiffAgainst::Booted => BOOTED_PROFILE,
+        };
+
+        // The system of the target host is often still in the local store from the previous
+        // deployment, and then the diff doesn't have to wait for the copy, if it can run here
+        let diff_is_local = util::diff_args(&self.common.diff_provider)
+            .first()
+            .is_some_and(|tool| util::ensure_tools(&[tool]).is_ok());
+        let local_base = match (&self.target_host, dry_run_commands) {
+            (Some(host), false) if diff_is_local => {
+                remote_store_path(host, base_profile).filter(|path| path.exists())
+            }
+            _ => None,
+        };
+        debug!("local_base: {local_base:?}");
+        if self.target_host.is_some() && local_base.is_none() {
+            background.wait()?;
+        }
+        let diff_host = match local_base {
+            Some(_) => None,
+            None => self.target_host.clone(),
+        };
+        let diff_base = local_base.unwrap_or_else(|| PathBuf::from(base_profile));
+
+        let show_diff = || {
+            commands::CommandBuilder::default()
+                .ssh(diff_host.clone())
+                .args(util::diff_args(&self.common.diff_provider))
+                .args([&diff_base, &target_profile])
+                .message("Comparing changes")
+                .build()?
+                .exec()
+                .wrap_err(PhaseError::Diff)
+        };
+        let show_closure = || {
+            commands::CommandBuilder::default()
+                .ssh(self.target_host.clone())
+                .args(["nix", "store", "diff-closures", base_profile])
+                .args([&target_profile])
+                .message("Comparing the closures")
+                .build()?
+                .exec()
+                .wrap_err(PhaseError::Diff)
+        };
+
+        // Only the local system can be checked, and nothing was built with --dry-run-commands
+        let is_current =
+            self.target_host.is_none() && !dry_run_commands && is_current_system(&target_profile);
+        summary.changed = !is_current;
+        let unchanged = !self.force && is_current;
+
+        let profile = match &self.profile_name {
+            Some(name) => Path::new(SYSTEM_PROFILES_DIR).join(name),
+            None => PathBuf::from(SYSTEM_PROFILE),
+        };
+        // nh os test activates without setting the profile, so the same configuration may still
+        // have to be switched to
+        let profile_is_current =
+            self.target_host.is_none() && !dry_run_commands && same_system(&profile, &toplevel);
+
+        if unchanged {
+            info!("System already up to date");
+        } else {
+            let diff_start = Instant::now();
+            show_diff()?;
+            timings.record("diff", diff_start);
+        }
+
+        background.wait()?;
+
+        // The bootloader may still point to another generation, so boot carries on, and switch
+        // does until the profile is set
+        let done = unchanged
+            && match rebuild_type {
+                Boot(_) => false,
+                Switch(_) => profile_is_current,
+                _ => true,
+            };
+        if done {
+            return Ok(());
+        }
+
+        if self.common.dry || matches!(rebuild_type, OsRebuildType::Build(_)) {
+            return Ok(());
+        }
+
+        // Remote hosts are always activated through sudo
+        let elevate = self.target_host.is_some() || !effective_uid.is_root();
+
+        #[cfg(target_os = "linux")]
+        if let DryActivate(_) = rebuild_type {
+            let switch_to_configuration =
+                target_profile.join("bin").join("switch-to-configuration");
+            let switch_to_configuration = switch_to_configuration.to_str().unwrap();
+
+            commands::CommandBuilder::default()
+                .ssh(self.target_host.clone())
+                .root(elevate)
+                .args([switch_to_configuration, "dry-activate"])
+                .message("Showing what activating the configuration would do")
+                .build()?
+                .exec()?;
+
+            return Ok(());
+        }
+
+        if self.common.ask {
+            let confirmation = util::confirm_diff("Apply the config?", show_diff, show_closure)?;
+
+            if !confirmation {
+                bail!(PhaseError::Declined);
+            }
+        }
+
+        let activate_start = Instant::now();
+
+        // The system to go back to if the activation fails
+        let previous_system = match self.rollback_on_failure && !dry_run_commands {
+            true => Some(self.current_system()?),
+            false => None,
+        };
+        // Set once the profile points to the new configuration, so that it gets rolled back too
+        let profile_set = std::cell::Cell::new(None);
+
+        let activation = (|| -> Result<()> {
+            #[cfg(target_os = "linux")]
+            // The running configuration is only set as the profile and added to the bootloader
+            if matches!(rebuild_type, Test(_) | Switch(_)) && !unchanged {
+                // !! Use the target profile aka spec-namespaced
+                let switch_to_configuration =
+                    target_profile.join("bin").join("switch-to-configuration");
+                let switch_to_configuration = switch_to_configuration.to_str().unwrap();
+
+                commands::CommandBuilder::default()
+                    .ssh(self.target_host.clone())
+                    .root(elevate)
+                    .args([switch_to_configuration, "test"])
+                    .message("Activating configuration")
+                    .keep_stderr(true)
+                    .build()?
+                    .exec()
+                    .wrap_err(PhaseError::Activation)?;
+
+                if let Test(_) = rebuild_type {
+                    info!(
+                        "The configuration wasn't added to the bootloader and won't persist across reboots, use nh os boot or nh os switch for that"
+                    );
+                }
+            }
+
+            if let Boot(_) | Switch(_) = rebuild_type {
+                let profile_str = profile.to_str().unwrap();
+
+                // A new named profile is created through elevation, as its directory is owned by root
+                let can_write = self.target_host.is_none()
+                    && profile.exists()
+                    && profile_is_writable(&profile)?;
+
+                if self.profile_name.is_some() {
+                    commands::CommandBuilder::default()
+                        .ssh(self.target_host.clone())
+                        .root(elevate && !can_write)
+                        .args(["mkdir", "-p", SYSTEM_PROFILES_DIR])
+                        .build()?
+                        .exec()
+                        .wrap_err(PhaseError::Activation)?;
+                }
+
+                let previous_generation = match previous_system {
+                    Some(_) => self.profile_generation(&profile),
+                    None => None,
+                };
+                commands::CommandBuilder::default()
+                    .ssh(self.target_host.clone())
+                    .root(elevate && !can_write)
+                    .args(["nix-env", "--profile", profile_str, "--set", toplevel_str])
+                    .build()?
+                    .exec()
+                    .wrap_err(PhaseError::Activation)?;
+                profile_set.set(Some((profile.clone(), can_write, previous_generation)));
+
+                // Trimmed before the bootloader step, so that its entries reflect the kept generations
+                if let Some(keep) = self.keep_generations {
+                    let count_before = match &self.target_host {
+                        Some(_) => None,
+                        None => Some(profile_generations(&profile)?.len()),
+                    };
+
+                    commands::CommandBuilder::default()
+                        .ssh(self.target_host.clone())
+                        .root(elevate && !can_write)
+                        .args(["nix-env", "--profile", profile_str, "--delete-generations"])
+                        .args([format!("+{keep}")])
+                        .message(format!("Keeping the {keep} newest generations"))
+                        .build()?
+                        .exec()
+                        .wrap_err(PhaseError::Activation)?;
+
+                    if let Some(count_before) = count_before {
+                        let count_after = profile_generations(&profile)?.len();
+                        let removed = count_before.saturating_sub(count_after);
+                        info!("Removed {removed} generation(s)");
+                    }
+                }
+
+                // !! Use the base profile aka no spec-namespace
+                #[cfg(target_os = "linux")]
+                {
+                    let switch_to_configuration =
+                        toplevel.join("bin").join("switch-to-configuration");
+                    let switch_to_configuration = switch_to_configuration.to_str().unwrap();
+
+                    commands::CommandBuilder::default()
+                        .envs(
+                            self.install_bootloader
+                                .then_some(("NIXOS_INSTALL_BOOTLOADER", "1")),
+                        )
+                        .ssh(self.target_host.clone())
+                        .root(elevate)
+                        .args([switch_to_configuration, "boot"])
+                        .message("Adding configuration to bootloader")
+                        .keep_stderr(true)
+                        .build()?
+                        .exec()
+                        .wrap_err(PhaseError::Activation)?;
+                }
+
+                #[cfg(target_os = "macos")]
+                {
+                    let activate_user = toplevel.join("activate-user");
+                    let activate_user = activate_user.to_str().unwrap();
+
+                    commands::CommandBuilder::default()
+                        .ssh(self.target_host.clone())
+                        .args([activate_user])
+                        .message("Activating configuration for user")
+                        .build()?
+                        .exec()
+                        .wrap_err(PhaseError::Activation)?;
+
+                    let activate = toplevel.join("activate");
+                    let activate = activate.to_str().unwrap();
+
+                    commands::CommandBuilder::default()
+                        .ssh(self.target_host.clone())
+                        .root(elevate)
+                        .args([activate])
+                        .message("Activating configuration")
+                        .keep_stderr(true)
+                        .build()?
+                        .exec()
+                        .wrap_err(PhaseError::Activation)?;
+                }
+            }
+            Ok(())
+        })();
+
+        if let Err(err) = activation {
+            if let Some(previous) = &previous_system {
+                self.roll_back(rebuild_type, previous, profile_set.take(), elevate);
+            }
+            return Err(err);
+        }
+
+        timings.record("activate", activate_start);
+
+        if !dry_run_commands {
+            let store_path = read_out_link()?;
+            history::record(&history::Entry::new(
+                &format!("os {}", action_name(rebuild_type)),
+                &self.flakeref,
+                &hostname.to_string_lossy(),
+                &store_path.to_string_lossy(),
+            ));
+        }
+
+        // The booted system of a remote host can't be inspected from here
+        #[cfg(target_os = "linux")]
+        if matches!(rebuild_type, Test(_) | Switch(_))
+            && self.target_host.is_none()
+            && !dry_run_commands
+        {
+            let changed = reboot_reasons(Path::new(BOOTED_PROFILE), Path::new(CURRENT_PROFILE));
+            if !changed.is_empty() {
+                warn!("Reboot required: {} changed", changed.join(", "));
+                summary.reboot_required = true;
+            }
+        }
+
+        // Only commit the lock file once it is known to produce a working system
+        self.commit_lock_file(&source, &updated_inputs)?;
+
+        if self.reboot && matches!(rebuild_type, Boot(_) | Switch(_)) {
+            #[cfg(target_os = "macos")]
+            tracing::warn!("Rebooting is not supported on Darwin, skipping");
+
+            #[cfg(target_os = "linux")]
+            {
+                if util::confirm("Reboot now?")? {
+                    commands::CommandBuilder::default()
+                        .ssh(self.target_host.clone())
+                        .root(elevate)
+                        .args(["systemctl", "reboot"])
+                        .message("Rebooting")
+                        .build()?
+                        .exec()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the configuration of a rebuild comes from
+#[derive(Debug, Clone)]
+struct FlakeSource {
+    /// Directory of a local flake, or the flakeref itself
+    flake_path: PathBuf,
+    /// Directory to run git in, for local flakes
+    git_dir: Option<String>,
+    /// Whether it's a flake, instead of a configuration from the channels
+    is_flake: bool,
+    /// Whether the directory is owned by root, so changing it needs elevation
+    elevation_required: bool,
+}
+
+/// Wall-clock time spent in each phase of a rebuild
+#[derive(Debug, Default)]
+struct Timings(Vec<(&'static str, Duration)>);
+
+impl Timings {
+    fn record(&mut self, phase: &'static str, start: Instant) {
+        self.0.push((phase, start.elapsed()));
+    }
+}
+
+impl std::fmt::Display for Timings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let phases: Vec<String> = self
+            .0
+            .iter()
+            .map(|(phase, duration)| {
+                let duration = Duration::from_secs(duration.as_secs());
+                format!("{phase} {}", humantime::format_duration(duration))
+            })
+            .collect();
+        write!(f, "{}", phases.join(", "))
+    }
+}
+
+impl OsReplArgs {
+    fn repl(&self) -> Result<()> {
+        let flake_path = Path::new(self.flakeref.as_str());
+        let has_flake_nix = !flake_path.is_dir() || flake_path.join("flake.nix").exists();
+
+        if self.no_flake || !has_flake_nix {
+            let (config_var, config_file) = CHANNEL_CONFIG;
+            let config_path = flake_path.join(config_file);
+            let mut cmd = commands::CommandBuilder::default();
+            cmd.args(["nix", "repl", "--file", CHANNEL_ENTRYPOINT]);
+            if config_path.exists() {
+                cmd.args([
+                    "-I".to_owned(),
+                    format!("{config_var}={}", config_path.display()),
+                ]);
+            }
+            return cmd
+                .message("Loading the configuration")
+                .interactive(true)
+                .build()?
+                .exec();
+        }
+
+        // getFlake needs an absolute path for local flakes
+        let flakeref = match fs::canonicalize(flake_path) {
+            Ok(path) => path.display().to_string(),
+            Err(_) => self.flakeref.deref().to_owned(),
+        };
+        let flake = format!("builtins.getFlake {}", nix_string(&flakeref));
+
+        let expr = if self.whole_flake {
+            flake
+        } else {
+            let hostname = match &self.hostname {
+                Some(h) => h.to_owned(),
+                None => hostname().context("Failed to get hostname")?.into(),
+            };
+            format!("({flake}).{CONFIGURATION_MODULE}.{hostname:?}")
+        };
+
+        commands::CommandBuilder::default()
+            .args(["nix", "repl", "--expr", &expr])
+            .message("Loading the configuration")
+            .interactive(true)
+            .build()?
+            .exec()
+    }
+}
+
+/// Quotes the string as a Nix string literal
+fn nix_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{escaped}\"")
+}
+
+impl OsRollbackArgs {
+    pub fn rollback(&self) -> Result<()> {
+        let effective_uid = nix::unistd::Uid::effective();
+
+        let current = current_generation(SYSTEM_PROFILE)?;
+        let generations = profile_generations(SYSTEM_PROFILE)?;
+        debug!("current generation: {current}");
+
+        let target = match self.to {
+            Some(number) => generations
+                .iter()
+                .find(|gen| gen.number == number)
+                .ok_or_else(|| eyre!("Generation {number} doesn't exist"))?,
+            None => generations
+                .iter()
+                .rev()
+                .find(|gen| gen.number < current)
+                .ok_or_else(|| eyre!("No generation older than the current one ({current})"))?,
+        };
+        debug!("target generation: {target:?}");
+
+        if target.number == current {
+            bail!("Generation {current} is already the current one");
+        }
+
+        commands::CommandBuilder::default()
+            .args(util::diff_args(&self.diff_provider))
+            .args([CURRENT_PROFILE, target.path.to_str().unwrap()])
+            .message("Comparing changes")
+            .build()?
+            .exec()
+            .wrap_err(PhaseError::Diff)?;
+
+        if self.dry {
+            return Ok(());
+        }
+
+        if self.ask {
+            let confirmation =
+                util::confirm(&format!("Roll back to generation {}?", target.number))?;
+
+            if !confirmation {
+                bail!(PhaseError::Declined);
+            }
+        }
+
+        let can_write = profile_is_writable(SYSTEM_PROFILE)?;
+        let number = target.number.to_string();
+        let profile_args = match self.to {
+            Some(_) => vec!["--switch-generation", &number],
+            None => vec!["--rollback"],
+        };
+
+        commands::CommandBuilder::default()
+            .root(!effective_uid.is_root() && !can_write)
+            .args(["nix-env", "--profile", SYSTEM_PROFILE])
+            .args(profile_args)
+            .message(format!("Rolling back to generation {}", target.number))
+            .build()?
+            .exec()
+            .wrap_err(PhaseError::Activation)?;
+
+        #[cfg(target_os = "linux")]
+        {
+            let switch_to_configuration = target.path.join("bin").join("switch-to-configuration");
+            let switch_to_configuration = switch_to_configuration.to_str().unwrap();
+
+            commands::CommandBuilder::default()
+                .root(!effective_uid.is_root())
+                .args([switch_to_configuration, "switch"])
+                .message("Activating configuration")
+                .build()?
+                .exec()
+                .wrap_err(PhaseError::Activation)?;
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            let activate_user = target.path.join("activate-user");
+            let activate_user = activate_user.to_str().unwrap();
+
+            commands::CommandBuilder::default()
+                .args([activate_user])
+                .message("Activating configuration for user")
+                .build()?
+                .exec()
+                .wrap_err(PhaseError::Activation)?;
+
+            let activate = target.path.join("activate");
+            let activate = activate.to_str().unwrap();
+
+            commands::CommandBuilder::default()
+                .root(!effective_uid.is_root())
+                .args([activate])
+                .message("Activating configuration")
+                .build()?
+                .exec()
+                .wrap_err(PhaseError::Activation)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl OsDiffArgs {
+    pub fn diff(&self) -> Result<()> {
+        let from = resolve_generation(&self.from)?;
+        let to = resolve_generation(&self.to)?;
+
+        commands::CommandBuilder::default()
+            .args(util::diff_args(&self.diff_provider))
+            .args([&from, &to])
+            .message(format!("Comparing {} to {}", self.from, self.to))
+            .build()?
+            .exec()?;
+
+        Ok(())
+    }
+}
+
+/// Resolves a generation number, or the symbolic "current" and "booted", to its path
+fn resolve_generation(name: &str) -> Result<PathBuf> {
+    match name {
+        "current" => Ok(PathBuf::from(CURRENT_PROFILE)),
+        "booted" => Ok(PathBuf::from(BOOTED_PROFILE)),
+        _ => {
+            let number: u32 = name.parse().with_context(|| {
+                format!("Expected a generation number, \"current\" or \"booted\", got {name:?}")
+            })?;
+            profile_generations(SYSTEM_PROFILE)?
+                .into_iter()
+                .find(|gen| gen.number == number)
+                .map(|gen| gen.path)
+                .ok_or_else(|| eyre!("Generation {number} doesn't exist"))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GenerationInfo {
+    number: u32,
+    date: String,
+    nixos_version: Option<String>,
+    kernel_version: Option<String>,
+    current: bool,
+}
+
+impl OsHistoryArgs {
+    fn history(&self) -> Result<()> {
+        let entries = history::read()?;
+        let recent: Vec<_> = entries.iter().rev().take(self.limit).collect();
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&recent)?);
+            return Ok(());
+        }
+
+        use owo_colors::OwoColorize;
+        println!(
+            "{:<22} {:<12} {:<24} {}",
+            "Date".bold(),
+            "Action".bold(),
+            "Configuration".bold(),
+            "Flake".bold()
+        );
+        for entry in recent {
+            println!(
+                "{:<22} {:<12} {:<24} {}",
+                entry.timestamp, entry.action, entry.configuration, entry.flakeref
+            );
+            println!("  {}", entry.store_path.dimmed());
+        }
+
+        Ok(())
+    }
+}
+
+impl OsListGenerationsArgs {
+    pub fn list(&self) -> Result<()> {
+        let current = current_generation(SYSTEM_PROFILE)?;
+
+        let mut infos = Vec::new();
+        for gen in profile_generations(SYSTEM_PROFILE)? {
+            let modified = gen
+                .path
+                .symlink_metadata()
+                .context("Checking symlink metadata")?
+                .modified()
+                .context("Reading modified time")?;
+
+            #[cfg(target_os = "linux")]
+            let version_file = "nixos-version";
+            #[cfg(target_os = "macos")]
+            let version_file = "darwin-version";
+
+            infos.push(GenerationInfo {
+                number: gen.number,
+                date: humantime::format_rfc3339_seconds(modified).to_string(),
+                nixos_version: fs::read_to_string(gen.path.join(version_file))
+                    .ok()
+                    .map(|v| v.trim().to_owned()),
+                kernel_version: kernel_version(&gen.path),
+                current: gen.number == current,
+            });
+        }
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&infos)?);
+            return Ok(());
+        }
+
+        use owo_colors::OwoColorize;
+        println!(
+            "{:<10} {:<22} {:<30} {}",
+            "Generation".bold(),
+            "Date".bold(),
+            "Version".bold(),
+            "Kernel".bold()
+        );
+        for info in infos.iter().rev() {
+            let line = format!(
+                "{:<10} {:<22} {:<30} {}",
+                info.number,
+                info.date,
+                info.nixos_version.as_deref().unwrap_or("-"),
+                info.kernel_version.as_deref().unwrap_or("-"),
+            );
+            if info.current {
+                println!("{} {}", line.green(), "(current)".green());
+            } else {
+                println!("{line}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads and parses a flake.lock, if it exists
+fn read_lock_file(path: &Path) -> Option<serde_json::Value> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// Store path the profile of the remote host points to, when it can be read
+fn remote_store_path(host: &str, profile: &str) -> Option<PathBuf> {
+    let output = commands::CommandBuilder::default()
+        .ssh(Some(host.to_owned()))
+        .args(["readlink", "-f", profile])
+        .build()
+        .ok()?
+        .exec_capture_split()
+        .ok()??;
+    let path = output.stdout.trim();
+    (output.status.success() && path.starts_with("/nix/store/")).then(|| PathBuf::from(path))
+}
+
+/// Names of the inputs whose locked revision differs between two flake.lock files
+fn changed_lock_inputs(before: &serde_json::Value, after: &serde_json::Value) -> Vec<String> {
+    let Some(nodes) = after["nodes"].as_object() else {
+        return Vec::new();
+    };
+
+    nodes
+        .iter()
+        .filter(|(name, node)| *name != "root" && before["nodes"][name]["locked"] != node["locked"])
+        .map(|(name, _)| name.to_owned())
+        .collect()
+}
+
+/// Reads the kernel version of a generation from its kernel modules directory
+fn kernel_version(generation: &Path) -> Option<String> {
+    generation
+        .join("kernel-modules/lib/modules")
+        .read_dir()
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .next()
+}
+
+/// A numbered generation link of a profile, like `/nix/var/nix/profiles/system-42-link`
+#[derive(Debug)]
+struct ProfileGeneration {
+    number: u32,
+    path: PathBuf,
+}
+
+/// Lists the generations of a profile, sorted by their number
+fn profile_generations<P: AsRef<Path>>(profile: P) -> Result<Vec<ProfileGeneration>> {
+    let profile = profile.as_ref();
+    let name = profile
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Checking profile's name")?;
+    let generation_regex = Regex::new(&format!(r"^{}-(\d+)-link$", regex::escape(name)))?;
+
+    let mut generations = Vec::new();
+    for entry in profile
+        .parent()
+        .context("Reading profile's parent dir")?
+        .read_dir()
+        .context("Reading profile's generations")?
+    {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        if let Some(caps) = generation_regex.captures(&file_name) {
+            generations.push(ProfileGeneration {
+                number: caps[1].parse()?,
+                path: path.clone(),
+            });
+        }
+    }
+
+    generations.sort_by_key(|gen| gen.number);
+    Ok(generations)
+}
+
+/// Gets the number of the generation a profile currently points to
+fn current_generation<P: AsRef<Path>>(profile: P) -> Result<u32> {
+    let profile = profile.as_ref();
+    let link = profile
+        .read_link()
+        .with_context(|| format!("Reading the profile link {}", profile.display()))?;
+    link_generation(&link.to_string_lossy())
+}
+
+/// Parses the generation number of a profile link target, like `system-42-link`
+fn link_generation(link: &str) -> Result<u32> {
+    let generation_regex = Regex::new(r"-(\d+)-link$")?;
+    let caps = generation_regex
+        .captures(link)
+        .with_context(|| format!("Failed to parse the generation of {link}"))?;
+
+    Ok(caps[1].parse()?)
+}
+
+/// Parts of the system that only take effect after a reboot, and differ between the two
+/// configurations
+#[cfg(target_os = "linux")]
+fn reboot_reasons(booted: &Path, current: &Path) -> Vec<&'static str> {
+    ["kernel", "initrd", "kernel-modules"]
+        .into_iter()
+        .filter(|name| {
+            match (
+                fs::read_link(booted.join(name)),
+                fs::read_link(current.join(name)),
+            ) {
+                (Ok(booted), Ok(current)) => booted != current,
+                _ => false,
+            }
+        })
+        .collect()
+}
+
+/// Names of the specialisations of a system configuration, sorted
+pub fn specialisations(toplevel: &Path) -> Vec<String> {
+    let mut names: Vec<String> = match toplevel.join("specialisation").read_dir() {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+/// Name of the subcommand of a rebuild, as nixos-rebuild knows it
+fn action_name(rebuild_type: &OsRebuildType) -> &'static str {
+    match rebuild_type {
+        Switch(_) => "switch",
+        Boot(_) => "boot",
+        Test(_) => "test",
+        Build(_) => "build",
+        DryActivate(_) => "dry-activate",
+        BuildVm(_) => "build-vm",
+        Rollback(_) => "rollback",
+        ListGenerations(_) => "list-generations",
+        Diff(_) => "diff",
+        Repl(_) => "repl",
+        History(_) => "history",
+        OsRebuildType::Info => "info",
+    }
+}
+
+/// Whether the profile is the configuration the system is running right now
+fn is_current_system(profile: &Path) -> bool {
+    same_system(profile, Path::new(CURRENT_PROFILE))
+}
+
+/// Whether both links resolve to the same configuration
+fn same_system(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Checks whether the current user can set the given profile without elevating
+fn profile_is_writable<P: AsRef<Path>>(profile: P) -> Result<bool> {
+    let profile = profile.as_ref();
+    let effective_uid = nix::unistd::Uid::effective();
+    let effective_gid = nix::unistd::Gid::effective();
+
+    let profile_metadata = fs::metadata(profile).context("Failed to get metadata of profile")?;
+    let profile_uid = nix::unistd::Uid::from_raw(profile_metadata.uid());
+    let profile_gid = nix::unistd::Gid::from_raw(profile_metadata.gid());
+    let can_write = !profile_metadata.permissions().readonly()
+        && (effective_uid == profile_uid || effective_gid == profile_gid);
+    debug!("{profile:?} is writable by user: {can_write}");
+
+    Ok(can_write)
+}
+
+#[test]
+fn test_nix_string() {
+    assert_eq!(nix_string("/etc/nixos"), r#""/etc/nixos""#);
+    assert_eq!(nix_string(r#"a"b\c"#), r#""a\"b\\c""#);
+    assert_eq!(nix_string("${x}"), r#""\${x}""#);
+}
+
+#[test]
+fn test_specialisations() {
+    let toplevel = tempfile::tempdir().unwrap();
+    assert!(specialisations(toplevel.path()).is_empty());
+
+    for name in ["work", "gaming"] {
+        fs::create_dir_all(toplevel.path().join("specialisation").join(name)).unwrap();
+    }
+    assert_eq!(specialisations(toplevel.path()), ["gaming", "work"]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_reboot_reasons() {
+    let booted = tempfile::tempdir().unwrap();
+    let current = tempfile::tempdir().unwrap();
+    for (dir, kernel) in [
+        (&booted, "/nix/store/a-linux"),
+        (&current, "/nix/store/b-linux"),
+    ] {
+        std::os::unix::fs::symlink(kernel, dir.path().join("kernel")).unwrap();
+        std::os::unix::fs::symlink("/nix/store/initrd", dir.path().join("initrd")).unwrap();
+    }
+    assert_eq!(reboot_reasons(booted.path(), current.path()), ["kernel"]);
+    assert!(reboot_reasons(booted.path(), booted.path()).is_empty());
+}
+
+#[test]
+fn test_same_system() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name);
+    for name in ["system-1", "system-2"] {
+        fs::create_dir(path(name)).unwrap();
+    }
+    std::os::unix::fs::symlink(path("system-1"), path("current")).unwrap();
+    std::os::unix::fs::symlink(path("system-2"), path("profile")).unwrap();
+
+    assert!(same_system(&path("current"), &path("system-1")));
+    assert!(!same_system(&path("current"), &path("profile")));
+    assert!(!same_system(&path("missing"), &path("missing")));
+}
+
+#[test]
+fn test_link_generation() {
+    assert_eq!(link_generation("system-42-link").unwrap(), 42);
+    assert_eq!(
+        link_generation("/nix/var/nix/profiles/system-5-link").unwrap(),
+        5
+    );
+    assert!(link_generation("/nix/store/abc-nixos-system").is_err());
 }
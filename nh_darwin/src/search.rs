@@ -1,11 +1,17 @@
-use std::{collections::HashMap, process::Stdio, time::Instant};
-
-use color_eyre::eyre::{eyre, Context, ContextCompat};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Instant,
+};
+
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat};
 use elasticsearch_dsl::*;
-use interface::{FlakeRef, SearchArgs};
+use interface::{FlakeRef, SearchArgs, SearchSort};
 use regex::Regex;
-use serde::Deserialize;
-use tracing::{debug, trace, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, info, trace, warn};
 
 use crate::*;
 
@@ -32,6 +38,112 @@ struct SearchResult {
     package_position: Option<String>,
 }
 
+/// Option as found in the search.nixos.org index
+#[derive(Debug, Deserialize)]
+struct OptionResult {
+    option_name: String,
+    option_type: Option<String>,
+    option_default: Option<String>,
+    option_description: Option<String>,
+}
+
+/// Option from the home-manager options documentation
+#[derive(Debug, Deserialize)]
+struct HmOption {
+    r#type: Option<String>,
+    default: Option<serde_json::Value>,
+    description: Option<serde_json::Value>,
+}
+
+const HOME_MANAGER_FLAKE: &str = "github:nix-community/home-manager";
+
+/// Text of a documentation value, which can be a plain string or a `{ _type, text }` literal
+fn literal_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Object(object) => match object.get("text") {
+            Some(serde_json::Value::String(text)) => text.clone(),
+            _ => value.to_string(),
+        },
+        _ => value.to_string(),
+    }
+}
+
+/// Summary of an option, as printed for --json
+#[derive(Debug, Serialize)]
+struct OptionSummary {
+    name: String,
+    r#type: Option<String>,
+    default: Option<String>,
+    description: Option<String>,
+}
+
+impl From<OptionResult> for OptionSummary {
+    fn from(option: OptionResult) -> Self {
+        OptionSummary {
+            name: option.option_name,
+            r#type: option.option_type,
+            default: option.option_default,
+            description: option.option_description,
+        }
+    }
+}
+
+/// Summary of a package, as printed for --json
+#[derive(Debug, Serialize)]
+struct PackageSummary {
+    attr: String,
+    version: String,
+    description: Option<String>,
+    homepage: Vec<String>,
+    license: Vec<String>,
+}
+
+/// Package as reported by `nix search --json`
+#[derive(Debug, Deserialize)]
+struct NixSearchResult {
+    version: String,
+    description: String,
+}
+
+impl SearchResult {
+    fn label(&self) -> String {
+        result_label(&self.package_attr_name, self.package_description.as_deref())
+    }
+
+    fn sort_key(&self) -> (&String, &String) {
+        (&self.package_attr_name, &self.package_pversion)
+    }
+}
+
+/// Sorts the results in place, relevance being the order they already come in
+fn sort_results<T>(results: &mut [T], sort: SearchSort, key: impl Fn(&T) -> (&String, &String)) {
+    match sort {
+        SearchSort::Relevance => {}
+        SearchSort::Name => results.sort_by(|a, b| key(a).0.cmp(key(b).0)),
+        SearchSort::Version => results.sort_by(|a, b| compare_versions(key(b).1, key(a).1)),
+    }
+}
+
+/// Compares versions by their dot or dash separated parts, numerically when both parts are numbers
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    let mut a_parts = a.split(['.', '-']);
+    let mut b_parts = b.split(['.', '-']);
+    loop {
+        let (a, b) = match (a_parts.next(), b_parts.next()) {
+            (Some(a), Some(b)) => (a, b),
+            (a, b) => return a.is_some().cmp(&b.is_some()),
+        };
+        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+}
+
 macro_rules! print_hyperlink {
     ($text:expr, $link:expr) => {
         print!("\x1b]8;;{}\x07", $link);
@@ -44,8 +156,65 @@ impl NHRunnable for SearchArgs {
     fn run(&self) -> Result<()> {
         trace!("args: {self:?}");
 
+        if let Some(flake) = &self.search_flake {
+            let mut packages = self.search_flake(flake)?;
+            sort_results(&mut packages, self.sort, |p| (&p.attr, &p.version));
+            packages.truncate(self.limit as usize);
+            if self.json {
+                println!("{}", serde_json::to_string(&packages)?);
+            } else if self.interactive {
+                let labels: Vec<_> = packages
+                    .iter()
+                    .map(|p| result_label(&p.attr, p.description.as_deref()))
+                    .collect();
+                if let Some(index) = pick("Package", &labels)? {
+                    print_choice(&packages[index].attr);
+                }
+            } else {
+                print_summaries(&packages);
+            }
+            return Ok(());
+        }
+
+        if self.options || self.hm_options {
+            let mut options = match self.hm_options {
+                true => self.query_hm_options()?,
+                false => self.query_options(&self.channel())?,
+            };
+            sort_results(&mut options, self.sort, |o| (&o.name, &o.name));
+            if self.json {
+                println!("{}", serde_json::to_string(&options)?);
+            } else {
+                if self.sort == SearchSort::Relevance {
+                    options.reverse();
+                }
+                print_options(&options);
+            }
+            return Ok(());
+        }
+
+        let channel = self.channel();
+        debug!(?channel);
+
+        if self.json {
+            let mut documents = self.query_documents(&channel)?;
+            sort_results(&mut documents, self.sort, SearchResult::sort_key);
+            let results: Vec<_> = documents
+                .into_iter()
+                .map(|elem| PackageSummary {
+                    attr: elem.package_attr_name,
+                    version: elem.package_pversion,
+                    description: elem.package_description,
+                    homepage: elem.package_homepage,
+                    license: elem.package_license_set,
+                })
+                .collect();
+            println!("{}", serde_json::to_string(&results)?);
+            return Ok(());
+        }
+
         let nixpkgs_path = std::thread::spawn(|| {
-            std::process::Command::new("nix")
+            std::process::Command::new(crate::commands::program("nix"))
                 .stderr(Stdio::inherit())
                 .args(["eval", "nixpkgs#path"])
                 .output()
@@ -54,6 +223,81 @@ impl NHRunnable for SearchArgs {
         // let mut nixpkgs_path = std::process::Command::new("nix")
         // .context("Evaluating the nixpkgs path for results positions")?;
 
+        println!("Querying search.nixos.org, with channel {}...", channel);
+        let then = Instant::now();
+        let mut documents = self.query_documents(&channel)?;
+        let elapsed = then.elapsed();
+        debug!(?elapsed);
+        println!("Took {}ms", elapsed.as_millis());
+
+        if self.open {
+            sort_results(&mut documents, self.sort, SearchResult::sort_key);
+            return open_homepage(&documents);
+        }
+
+        if self.interactive {
+            sort_results(&mut documents, self.sort, SearchResult::sort_key);
+            let labels: Vec<_> = documents.iter().map(SearchResult::label).collect();
+            if let Some(index) = pick("Package", &labels)? {
+                print_choice(&documents[index].package_attr_name);
+            }
+            return Ok(());
+        }
+
+        // Keep the most relevant results close to the prompt
+        if self.sort == SearchSort::Relevance {
+            println!("Most relevant results at the end");
+            documents.reverse();
+        } else {
+            sort_results(&mut documents, self.sort, SearchResult::sort_key);
+        }
+        println!();
+
+        let nixpkgs_path = String::from_utf8(
+            nixpkgs_path
+                .join()
+                .unwrap()
+                .context("Evaluating the nixpkgs path location")?
+                .stdout,
+        )
+        .unwrap();
+
+        print_documents(&documents, &nixpkgs_path);
+
+        Ok(())
+    }
+}
+
+impl SearchArgs {
+    /// Channel to search in, from the flag or the nixpkgs input of the flake
+    fn channel(&self) -> String {
+        match (&self.channel, &self.flake) {
+            (Some(c), _) => c.clone(),
+            (None, Some(f)) => {
+                let c = my_nix_branch(f);
+                match c {
+                    Ok(s) => s,
+                    Err(err) => {
+                        warn!(
+                            "Failed to read the nixpkgs input for the flake {}",
+                            f.as_str()
+                        );
+                        for e in err.chain() {
+                            warn!("{}", e);
+                        }
+                        String::from("nixos-unstable")
+                    }
+                }
+            }
+            (None, None) => {
+                debug!("Using default search channel");
+                String::from("nixos-unstable")
+            }
+        }
+    }
+
+    /// Queries search.nixos.org, returning the results from most to least relevant
+    fn query_documents(&self, channel: &str) -> Result<Vec<SearchResult>> {
         let query = Search::new().from(0).size(self.limit).query(
             Query::bool().filter(Query::term("type", "package")).must(
                 Query::dis_max()
@@ -88,33 +332,105 @@ impl NHRunnable for SearchArgs {
             ),
         );
 
-        let channel: String = match (&self.channel, &self.flake) {
-            (Some(c), _) => c.clone(),
-            (None, Some(f)) => {
-                let c = my_nix_branch(f);
-                match c {
-                    Ok(s) => s,
-                    Err(err) => {
-                        warn!(
-                            "Failed to read the nixpkgs input for the flake {}",
-                            f.as_str()
-                        );
-                        for e in err.chain() {
-                            warn!("{}", e);
-                        }
-                        String::from("nixos-unstable")
-                    }
-                }
-            }
-            (None, None) => {
-                debug!("Using default search channel");
-                String::from("nixos-unstable")
-            }
-        };
-        debug!(?channel);
+        self.query_backend(channel, &query, "package")
+    }
 
-        println!("Querying search.nixos.org, with channel {}...", channel);
-        let then = Instant::now();
+    /// Queries search.nixos.org for options, returning them from most to least relevant
+    fn query_options(&self, channel: &str) -> Result<Vec<OptionSummary>> {
+        let query = Search::new().from(0).size(self.limit).query(
+            Query::bool().filter(Query::term("type", "option")).must(
+                Query::dis_max()
+                    .tie_breaker(0.7)
+                    .query(
+                        Query::multi_match(
+                            [
+                                "option_name^6",
+                                "option_name.*^3.5999999999999996",
+                                "option_description^1",
+                                "option_description.*^0.6",
+                                "flake_name^0.5",
+                                "flake_name.*^0.3",
+                            ],
+                            self.query.as_str(),
+                        )
+                        .r#type(TextQueryType::CrossFields)
+                        .analyzer("whitespace")
+                        .auto_generate_synonyms_phrase_query(false)
+                        .operator(Operator::And),
+                    )
+                    .query(
+                        Query::wildcard("option_name", format!("*{}*", self.query))
+                            .case_insensitive(true),
+                    ),
+            ),
+        );
+
+        let options: Vec<OptionResult> = self.query_backend(channel, &query, "option")?;
+        Ok(options.into_iter().map(OptionSummary::from).collect())
+    }
+
+    /// Searches the options documentation of home-manager, options matching by name first
+    fn query_hm_options(&self) -> Result<Vec<OptionSummary>> {
+        let output = commands::CommandBuilder::default()
+            .args(["nix", "build", "--no-link", "--print-out-paths"])
+            .args([format!("{HOME_MANAGER_FLAKE}#docs-json")])
+            .message("Building the home-manager options documentation")
+            .build()?
+            .exec_capture_split()?
+            .context("Building the home-manager options")?;
+        if !output.status.success() {
+            bail!(
+                "Failed to build the home-manager options:\n{}",
+                output.stderr.trim()
+            );
+        }
+
+        let path = PathBuf::from(output.stdout.trim()).join("share/doc/home-manager/options.json");
+        let file = std::fs::File::open(&path).with_context(|| format!("Opening {path:?}"))?;
+        let options: BTreeMap<String, HmOption> =
+            serde_json::from_reader(std::io::BufReader::new(file))
+                .context("Parsing the home-manager options")?;
+
+        let query = self.query.to_lowercase();
+        let mut matches: Vec<_> = options
+            .into_iter()
+            .map(|(name, option)| OptionSummary {
+                name,
+                r#type: option.r#type,
+                default: option.default.as_ref().map(literal_text),
+                description: option.description.as_ref().map(literal_text),
+            })
+            .filter(|option| {
+                let description = option.description.as_deref().unwrap_or_default();
+                option.name.to_lowercase().contains(&query)
+                    || description.to_lowercase().contains(&query)
+            })
+            .collect();
+        // Stable, so each group stays sorted by name
+        matches.sort_by_key(|option| !option.name.to_lowercase().contains(&query));
+        matches.truncate(self.limit as usize);
+
+        Ok(matches)
+    }
+
+    fn query_backend<T: DeserializeOwned>(
+        &self,
+        channel: &str,
+        query: &Search,
+        kind: &str,
+    ) -> Result<Vec<T>> {
+        let cache_path = cache_path(channel, query).filter(|_| !self.no_cache);
+        if let Some(body) = cache_path
+            .as_deref()
+            .and_then(|p| read_cache(p, *self.cache_ttl))
+        {
+            debug!("Using the cached results from {cache_path:?}");
+            let parsed_response: SearchResponse =
+                serde_json::from_str(&body).context("parsing the cached search response")?;
+            return parsed_response
+                .documents::<T>()
+                .with_context(|| format!("parsing {kind} search document"));
+        }
 
         let client = reqwest::blocking::Client::new();
         let req = client
@@ -124,7 +440,7 @@ impl NHRunnable for SearchArgs {
                 "https://search.nixos.org/backend/latest-42-{}/_search",
                 channel
             ))
-            .json(&query)
+            .json(query)
             .header("User-Agent", format!("nh/{}", crate::NH_VERSION))
             // Hardcoded upstream
             // https://github.com/NixOS/nixos-search/blob/744ec58e082a3fcdd741b2c9b0654a0f7fda4603/frontend/src/index.js
@@ -137,84 +453,258 @@ impl NHRunnable for SearchArgs {
         let response = client
             .execute(req)
             .context("querying the elasticsearch API")?;
-        let elapsed = then.elapsed();
-        debug!(?elapsed);
         trace!(?response);
-        println!("Took {}ms", elapsed.as_millis());
-        println!("Most relevant results at the end");
-        println!();
-
-        let parsed_response: SearchResponse = response
-            .json()
+        let body = response.text().context("reading the search response")?;
+        let parsed_response: SearchResponse = serde_json::from_str(&body)
             .context("parsing response into the elasticsearch format")?;
         trace!(?parsed_response);
 
         let documents = parsed_response
-            .documents::<SearchResult>()
-            .context("parsing search document")?;
+            .documents::<T>()
+            .with_context(|| format!("parsing {kind} search document"))?;
+
+        // Only cache responses that could be parsed
+        if let Some(path) = &cache_path {
+            let written = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(path, &body));
+            if let Err(error) = written {
+                debug!(?path, ?error, "Failed to cache the search response");
+            }
+        }
 
-        let hyperlinks = supports_hyperlinks::supports_hyperlinks();
-        debug!(?hyperlinks);
+        Ok(documents)
+    }
 
-        let nixpkgs_path = String::from_utf8(
-            nixpkgs_path
-                .join()
-                .unwrap()
-                .context("Evaluating the nixpkgs path location")?
-                .stdout,
-        )
-        .unwrap();
+    /// Searches the packages of a flake with nix search, sorted by attribute, which also stands
+    /// in for the relevance
+    fn search_flake(&self, flake: &FlakeRef) -> Result<Vec<PackageSummary>> {
+        let output = commands::CommandBuilder::default()
+            .args(["nix", "search", "--json", flake.as_str(), &self.query])
+            .message(format!("Searching the packages of {}", flake.as_str()))
+            .build()?
+            .exec_capture_split()?
+            .context("Searching the flake")?;
+        if !output.status.success() {
+            bail!(
+                "Failed to search {}:\n{}",
+                flake.as_str(),
+                output.stderr.trim()
+            );
+        }
 
-        for elem in documents.iter().rev() {
-            println!();
-            use owo_colors::OwoColorize;
-            trace!("{elem:#?}");
+        let results: BTreeMap<String, NixSearchResult> =
+            serde_json::from_str(&output.stdout).context("Parsing the output of nix search")?;
+
+        Ok(results
+            .into_iter()
+            .map(|(attr, result)| {
+                // Drop the packages.<system> or legacyPackages.<system> prefix
+                let attr = match attr
+                    .split_once('.')
+                    .and_then(|(_, rest)| rest.split_once('.'))
+                {
+                    Some((_, attr)) => attr.to_owned(),
+                    None => attr,
+                };
+                PackageSummary {
+                    attr,
+                    version: result.version,
+                    description: Some(result.description).filter(|d| !d.is_empty()),
+                    // nix search doesn't report these
+                    homepage: Vec::new(),
+                    license: Vec::new(),
+                }
+            })
+            .collect())
+    }
+}
+
+/// Lets the user pick one of the results and opens its homepage. Without a terminal to ask on, the
+/// homepage of the first result is printed instead
+fn open_homepage(documents: &[SearchResult]) -> Result<()> {
+    let documents: Vec<_> = documents
+        .iter()
+        .filter(|elem| !elem.package_homepage.is_empty())
+        .collect();
+    if documents.is_empty() {
+        bail!("None of the results have a homepage");
+    }
+
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        println!("{}", documents[0].package_homepage[0]);
+        return Ok(());
+    }
 
-            print!("{}", elem.package_attr_name.blue());
-            let v = &elem.package_pversion;
-            if !v.is_empty() {
-                print!(" ({})", v.green());
+    let labels: Vec<_> = documents.iter().map(|elem| elem.label()).collect();
+    let Some(index) = pick("Package to open the homepage of", &labels)? else {
+        return Ok(());
+    };
+
+    #[cfg(target_os = "linux")]
+    let opener = "xdg-open";
+    #[cfg(target_os = "macos")]
+    let opener = "open";
+
+    let url = &documents[index].package_homepage[0];
+    commands::CommandBuilder::default()
+        .args([opener, url])
+        .message(format!("Opening {url}"))
+        .build()?
+        .exec()
+}
+
+/// Asks the user to pick one of the items, filtering them as they type
+fn pick(prompt: &str, labels: &[String]) -> Result<Option<usize>> {
+    if !std::io::stdin().is_terminal() {
+        bail!("Picking a result needs an interactive terminal");
+    }
+    if labels.is_empty() {
+        bail!("No results to pick from");
+    }
+
+    Ok(dialoguer::FuzzySelect::new()
+        .with_prompt(prompt)
+        .items(labels)
+        .default(0)
+        .interact_opt()?)
+}
+
+fn result_label(attr: &str, description: Option<&str>) -> String {
+    match description {
+        Some(desc) => format!("{attr} - {}", desc.replace('\n', " ")),
+        None => attr.to_owned(),
+    }
+}
+
+/// Prints the chosen attribute for scripts, and how to install it for humans
+fn print_choice(attr: &str) {
+    println!("{attr}");
+    info!("Add it to your configuration with: environment.systemPackages = [ pkgs.{attr} ];");
+}
+
+fn print_options(options: &[OptionSummary]) {
+    use owo_colors::OwoColorize;
+    for option in options {
+        println!();
+        println!("{}", option.name.blue());
+        if let Some(r#type) = &option.r#type {
+            println!("  Type: {}", r#type.green());
+        }
+        if let Some(default) = &option.default {
+            println!("  Default: {}", default.trim());
+        }
+        if let Some(desc) = &option.description {
+            let desc = desc.replace('\n', " ");
+            for line in textwrap::wrap(desc.trim(), textwrap::Options::with_termwidth()) {
+                println!("  {}", line);
             }
+        }
+    }
+}
 
-            println!();
+fn print_summaries(packages: &[PackageSummary]) {
+    use owo_colors::OwoColorize;
+    for package in packages {
+        println!();
+        print!("{}", package.attr.blue());
+        if !package.version.is_empty() {
+            print!(" ({})", package.version.green());
+        }
+        println!();
 
-            if let Some(ref desc) = elem.package_description {
-                let desc = desc.replace('\n', " ");
-                for line in textwrap::wrap(&desc, textwrap::Options::with_termwidth()) {
-                    println!("  {}", line);
-                }
+        if let Some(desc) = &package.description {
+            let desc = desc.replace('\n', " ");
+            for line in textwrap::wrap(&desc, textwrap::Options::with_termwidth()) {
+                println!("  {}", line);
             }
+        }
+    }
+}
 
-            for url in elem.package_homepage.iter() {
-                print!("  Homepage: ");
-                if hyperlinks {
-                    print_hyperlink!(url, url);
-                } else {
-                    println!("{}", url);
-                }
+fn print_documents(documents: &[SearchResult], nixpkgs_path: &str) {
+    let hyperlinks = supports_hyperlinks::supports_hyperlinks();
+    debug!(?hyperlinks);
+
+    for elem in documents {
+        println!();
+        use owo_colors::OwoColorize;
+        trace!("{elem:#?}");
+
+        print!("{}", elem.package_attr_name.blue());
+        let v = &elem.package_pversion;
+        if !v.is_empty() {
+            print!(" ({})", v.green());
+        }
+
+        println!();
+
+        if let Some(ref desc) = elem.package_description {
+            let desc = desc.replace('\n', " ");
+            for line in textwrap::wrap(&desc, textwrap::Options::with_termwidth()) {
+                println!("  {}", line);
+            }
+        }
+
+        for url in elem.package_homepage.iter() {
+            print!("  Homepage: ");
+            if hyperlinks {
+                print_hyperlink!(url, url);
+            } else {
+                println!("{}", url);
             }
+        }
 
-            if let Some(position) = &elem.package_position {
-                print!("  Position: ");
-                if hyperlinks {
-                    let postion_trimmed = position
-                        .split(':')
-                        .next()
-                        .expect("Removing line number from position");
-
-                    print_hyperlink!(position, format!("file://{nixpkgs_path}/{postion_trimmed}"));
-                } else {
-                    println!("{}", position);
-                }
+        if !elem.package_license_set.is_empty() {
+            println!("  License: {}", elem.package_license_set.join(", "));
+        }
+
+        if let Some(position) = &elem.package_position {
+            print!("  Position: ");
+            if hyperlinks {
+                let postion_trimmed = position
+                    .split(':')
+                    .next()
+                    .expect("Removing line number from position");
+
+                print_hyperlink!(position, format!("file://{nixpkgs_path}/{postion_trimmed}"));
+            } else {
+                println!("{}", position);
             }
         }
+    }
+}
 
-        Ok(())
+/// Cache file for the results of a query, under $XDG_CACHE_HOME/nh
+fn cache_path(channel: &str, query: &Search) -> Option<PathBuf> {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    channel.hash(&mut hasher);
+    serde_json::to_string(query).ok()?.hash(&mut hasher);
+
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| {
+            dir.join("nh")
+                .join("search")
+                .join(format!("{:016x}.json", hasher.finish()))
+        })
+}
+
+/// Reads the cache file if it's younger than the TTL
+fn read_cache(path: &Path, ttl: std::time::Duration) -> Option<String> {
+    let age = path.metadata().ok()?.modified().ok()?.elapsed().ok()?;
+    if age > ttl {
+        return None;
     }
+    std::fs::read_to_string(path).ok()
 }
 
 fn my_nix_branch(flake: &FlakeRef) -> Result<String> {
-    let mut child = std::process::Command::new("nix")
+    let mut child = std::process::Command::new(crate::commands::program("nix"))
         .args(["flake", "metadata", "--json"])
         .arg(flake.as_str())
         .stderr(Stdio::inherit())
@@ -252,10 +742,11 @@ fn supported_branch<S: AsRef<str>>(branch: S) -> bool {
     }
 
     let re = Regex::new(r"nixos-[0-9]+\.[0-9]+").unwrap();
-    return re.is_match(branch);
+    re.is_match(branch)
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_supported_branch() {
     assert_eq!(supported_branch("nixos-unstable"), true);
     assert_eq!(supported_branch("nixos-unstable-small"), false);
@@ -265,6 +756,17 @@ fn test_supported_branch() {
     assert_eq!(supported_branch("nixpks-21.11-darwin"), false);
 }
 
+#[test]
+fn test_compare_versions() {
+    use std::cmp::Ordering;
+    assert_eq!(compare_versions("1.10.0", "1.9.2"), Ordering::Greater);
+    assert_eq!(compare_versions("2.0", "2.0.1"), Ordering::Less);
+    assert_eq!(
+        compare_versions("4.2-unstable", "4.2-unstable"),
+        Ordering::Equal
+    );
+}
+
 #[derive(Debug, Deserialize, Clone)]
 struct FlakeMetadata {
     locks: FlakeLocks,
@@ -1,14 +1,17 @@
 extern crate semver;
 
+use crate::interface::{ColorMode, GlobalArgs};
 use color_eyre::{eyre, Result};
+use once_cell::sync::OnceCell;
 use semver::Version;
-use tracing::debug;
+use tracing::{debug, info, warn};
 use which::which;
 
 use std::ffi::OsString;
+use std::io::IsTerminal;
 use std::process::Command;
 use std::str;
-use subprocess::{Exec, Redirection};
+use subprocess::{Exec, ExitStatus, Redirection};
 
 /// Compares two semantic versions and returns their order.
 ///
@@ -16,6 +19,9 @@ use subprocess::{Exec, Redirection};
 /// It returns an `Ordering` indicating whether the current version is less than, equal to, or
 /// greater than the target version.
 ///
+/// Pre-release and build suffixes are ignored, as nix emits versions like `2.19.0pre20230901` for
+/// development builds that already behave like the release.
+///
 /// # Arguments
 ///
 /// * `current` - A string slice representing the current version.
@@ -25,40 +31,102 @@ use subprocess::{Exec, Redirection};
 ///
 /// * `Result<std::cmp::Ordering>` - The comparison result.
 pub fn compare_semver(current: &str, target: &str) -> Result<std::cmp::Ordering> {
-    let current = Version::parse(current)?;
-    let target = Version::parse(target)?;
+    let current = parse_version(current)?;
+    let target = parse_version(target)?;
 
     Ok(current.cmp(&target))
 }
 
+/// Parses the major, minor and patch numbers of a version, dropping whatever comes after them
+fn parse_version(version: &str) -> Result<Version> {
+    let re = regex::Regex::new(r"^(\d+)\.(\d+)(?:\.(\d+))?")?;
+    let captures = re
+        .captures(version)
+        .ok_or_else(|| eyre::eyre!("Failed to parse version {version:?}"))?;
+    let number = |i| captures.get(i).map_or(Ok(0), |m| m.as_str().parse());
+
+    Ok(Version::new(number(1)?, number(2)?, number(3)?))
+}
+
+static NIX_VERSION: OnceCell<String> = OnceCell::new();
+
 /// Retrieves the installed Nix version as a string.
 ///
 /// This function executes the `nix --version` command, parses the output to extract the version string,
 /// and returns it. If the version string cannot be found or parsed, it returns an error.
+/// The version is only queried once, later calls reuse it.
 ///
 /// # Returns
 ///
 /// * `Result<String>` - The Nix version string or an error if the version cannot be retrieved.
 pub fn get_nix_version() -> Result<String> {
-    let output = Command::new("nix").arg("--version").output()?;
+    cached(&NIX_VERSION, query_nix_version)
+}
+
+/// Gets the value of the cell, initializing it with the query if it's empty
+fn cached(cell: &OnceCell<String>, query: impl FnOnce() -> Result<String>) -> Result<String> {
+    cell.get_or_try_init(query).cloned()
+}
+
+fn query_nix_version() -> Result<String> {
+    let output = Command::new(crate::commands::program("nix"))
+        .arg("--version")
+        .output()?;
 
     let output_str = str::from_utf8(&output.stdout)?;
-    let version_str = output_str
+    extract_nix_version(output_str)
+        .ok_or_else(|| eyre::eyre!("Failed to extract the version from {:?}", output_str.trim()))
+}
+
+/// Finds the Nix version in the output of `nix --version`.
+///
+/// Forks print their own name and version first, like `nix (Determinate Nix 3.6.2) 2.29.0`, so
+/// the last version of the line is the one of the Nix interface.
+fn extract_nix_version(output: &str) -> Option<String> {
+    let re = regex::Regex::new(r"\d+\.\d+(\.\d+)?").unwrap();
+    output
         .lines()
-        .next()
-        .ok_or_else(|| eyre::eyre!("No version string found"))?;
+        .find_map(|line| re.find_iter(line).last())
+        .map(|version| version.as_str().to_owned())
+}
 
-    // Extract the version substring using a regular expression
-    let re = regex::Regex::new(r"\d+\.\d+\.\d+")?;
-    if let Some(captures) = re.captures(version_str) {
-        let version = captures
-            .get(0)
-            .ok_or_else(|| eyre::eyre!("No version match found"))?
-            .as_str();
-        return Ok(version.to_string());
+/// Retrieves the short hostname of the machine.
+///
+/// This is the name used to pick the configuration to build from a flake, when one isn't
+/// specified explicitly. Any domain suffix is dropped, like the `.local` macOS tends to add, and
+/// on macOS the `LocalHostName` is used when the hostname is empty.
+///
+/// # Returns
+///
+/// * `Result<String>` - The hostname or an error if it can't be retrieved or isn't valid UTF-8.
+pub fn hostname() -> Result<String> {
+    let name = nix::unistd::gethostname()?
+        .into_string()
+        .map_err(|name| eyre::eyre!("Hostname {name:?} is not valid UTF-8"))?;
+
+    #[cfg(target_os = "macos")]
+    let name = match short_hostname(&name).is_empty() {
+        true => {
+            let output = Command::new("scutil")
+                .args(["--get", "LocalHostName"])
+                .output()?;
+            String::from_utf8(output.stdout)?
+        }
+        false => name,
+    };
+
+    let name = short_hostname(&name);
+    if name.is_empty() {
+        return Err(eyre::eyre!("The hostname is empty"));
     }
 
-    Err(eyre::eyre!("Failed to extract version"))
+    Ok(name.to_owned())
+}
+
+/// Drops the domain from a hostname
+fn short_hostname(name: &str) -> &str {
+    let name = name.trim();
+    name.split_once('.').map_or(name, |(short, _)| short)
 }
 
 /// Gets a path to a previlege elevation program based on what is available in the system.
@@ -79,7 +147,7 @@ pub fn get_nix_version() -> Result<String> {
 /// # Returns
 ///
 /// * `Result<OsString>` - The absolute path to the privilege elevation program binary or an error if a
-/// program can't be found.
+///   program can't be found.
 pub fn get_elevation_program() -> Result<(OsString, Vec<OsString>)> {
     let has_doas = which("doas");
     if let Ok(path) = has_doas {
@@ -115,3 +183,520 @@ pub fn get_elevation_program() -> Result<(OsString, Vec<OsString>)> {
 
     Err(eyre::eyre!("No elevation strategy found"))
 }
+
+/// Sends a desktop notification, using `notify-send` on Linux and `osascript` on macOS.
+///
+/// Failing to send the notification is not an error, as it is purely informational and the
+/// notification tools may not be installed.
+///
+/// # Arguments
+///
+/// * `summary` - The title of the notification.
+/// * `body` - The text of the notification.
+pub fn send_notification(summary: &str, body: &str) {
+    #[cfg(target_os = "linux")]
+    let cmd = Exec::cmd("notify-send").args(&["--app-name", "nh", summary, body]);
+    #[cfg(target_os = "macos")]
+    let cmd = Exec::cmd("osascript").args(&[
+        "-e",
+        &format!("display notification {body:?} with title {summary:?}"),
+    ]);
+
+    debug!(?cmd);
+    match cmd
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe)
+        .join()
+    {
+        Ok(ExitStatus::Exited(0)) => (),
+        other => debug!(?other, "Failed to send notification"),
+    }
+}
+
+/// Whether colored output was disabled through `NO_COLOR`, see <https://no-color.org>
+pub fn no_color() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+/// Formats a size in bytes with binary units, e.g. "1.5 GiB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+/// Checks the free space of the store before building, warning below `warn` and failing below
+/// `min`. The space can't always be read, like with a remote store, which isn't an error
+pub fn check_free_space(warn: u64, min: Option<u64>) -> Result<()> {
+    let free = match free_space(std::path::Path::new("/nix/store")) {
+        Ok(free) => free,
+        Err(err) => {
+            debug!(?err, "Not checking the free space of the store");
+            return Ok(());
+        }
+    };
+    debug!(free);
+
+    if let Some(min) = min.filter(|min| free < *min) {
+        eyre::bail!(
+            "Only {} free for the store, less than the {} of --min-free. nh clean can free some space",
+            format_size(free),
+            format_size(min)
+        );
+    }
+    if free < warn {
+        warn!(
+            "Only {} free for the store, the build may run out of space. nh clean can free some",
+            format_size(free)
+        );
+    }
+    Ok(())
+}
+
+/// Prefixes of the temporary directories holding the out-links of the rebuilds
+pub const TEMPDIR_PREFIXES: [&str; 2] = ["nh-os-", "nh-home-"];
+
+/// Locks the directory, shared by the rebuild using it or exclusively by `nh clean --tmp`, which
+/// only removes the directories no rebuild holds. Returns None when it's locked the other way
+pub fn try_lock_dir(
+    dir: &std::path::Path,
+    exclusive: bool,
+) -> Result<Option<nix::fcntl::Flock<std::fs::File>>> {
+    use nix::fcntl::{Flock, FlockArg};
+
+    let file = std::fs::File::open(dir)?;
+    let arg = match exclusive {
+        true => FlockArg::LockExclusiveNonblock,
+        false => FlockArg::LockSharedNonblock,
+    };
+    match Flock::lock(file, arg) {
+        Ok(lock) => Ok(Some(lock)),
+        Err((_, nix::errno::Errno::EWOULDBLOCK)) => Ok(None),
+        Err((_, errno)) => Err(eyre::eyre!(errno).wrap_err(format!("Locking {}", dir.display()))),
+    }
+}
+
+/// Space available to unprivileged users on the filesystem containing the path, in bytes
+pub fn free_space(path: &std::path::Path) -> Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+#[test]
+fn test_nix_version_cached() {
+    let cell = OnceCell::new();
+    let mut queries = 0;
+    for _ in 0..3 {
+        let version = cached(&cell, || {
+            queries += 1;
+            Ok("2.18.1".to_owned())
+        });
+        assert_eq!(version.unwrap(), "2.18.1");
+    }
+    assert_eq!(queries, 1);
+}
+
+#[test]
+fn test_extract_nix_version() {
+    assert_eq!(
+        extract_nix_version("nix (Nix) 2.18.1\n").as_deref(),
+        Some("2.18.1")
+    );
+    assert_eq!(
+        extract_nix_version("nix (Lix, like Nix) 2.90.0\n").as_deref(),
+        Some("2.90.0")
+    );
+    assert_eq!(
+        extract_nix_version("nix (Determinate Nix 3.6.2) 2.29.0\n").as_deref(),
+        Some("2.29.0")
+    );
+    assert_eq!(
+        extract_nix_version("nix (Lix, like Nix) 2.91.0-dev-pre20240517\n").as_deref(),
+        Some("2.91.0")
+    );
+    assert_eq!(extract_nix_version("nix (Nix) unstable\n"), None);
+}
+
+#[test]
+fn test_compare_semver() {
+    use std::cmp::Ordering;
+    assert_eq!(compare_semver("2.18.1", "2.19.0").unwrap(), Ordering::Less);
+    assert_eq!(
+        compare_semver("2.19.0-pre", "2.19.0").unwrap(),
+        Ordering::Equal
+    );
+    assert_eq!(
+        compare_semver("2.19.0pre20230901_abcdef", "2.19.0").unwrap(),
+        Ordering::Equal
+    );
+    assert_eq!(
+        compare_semver("2.20.0pre-git", "2.19.0").unwrap(),
+        Ordering::Greater
+    );
+    assert_eq!(
+        compare_semver("2.19.0+commit", "2.19.0").unwrap(),
+        Ordering::Equal
+    );
+    assert!(compare_semver("nix", "2.19.0").is_err());
+}
+
+#[test]
+fn test_short_hostname() {
+    assert_eq!(short_hostname("macbook"), "macbook");
+    assert_eq!(short_hostname("macbook.local"), "macbook");
+    assert_eq!(short_hostname("server.example.com\n"), "server");
+    assert_eq!(short_hostname(""), "");
+}
+
+/// Command line of the diff provider, passing the color choice to nvd which doesn't follow NO_COLOR
+pub fn diff_args(provider: &str) -> Vec<String> {
+    let mut args = diff_command(provider);
+    let color = nvd_color(GlobalArgs::get().color, no_color());
+    if let (Some(color), Some("nvd")) = (color, args.first().map(String::as_str)) {
+        args.insert(1, color.to_owned());
+    }
+    args
+}
+
+/// Color flag of nvd for the color mode, where auto leaves nvd to detect the terminal unless
+/// NO_COLOR is set
+fn nvd_color(mode: ColorMode, no_color: bool) -> Option<&'static str> {
+    match mode {
+        ColorMode::Auto if no_color => Some("--color=never"),
+        ColorMode::Auto => None,
+        ColorMode::Always => Some("--color=always"),
+        ColorMode::Never => Some("--color=never"),
+    }
+}
+
+/// Checks that the programs can be found, reporting all the missing ones at once instead of
+/// failing halfway through with a spawn error
+pub fn ensure_tools<S: AsRef<str>>(tools: &[S]) -> Result<()> {
+    let missing: Vec<&str> = tools
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|tool| which(crate::commands::program(tool)).is_err())
+        .collect();
+    debug!(?missing);
+
+    if !missing.is_empty() {
+        eyre::bail!(
+            "Couldn't find {} in the PATH, which {} needed for this command",
+            missing.join(", "),
+            if missing.len() == 1 { "is" } else { "are" }
+        );
+    }
+    Ok(())
+}
+
+/// Warns about the uncommitted changes in the git repository of a local flake, which nix builds
+/// as they are, and fails if `refuse` is set. Directories outside of git are left alone, and the
+/// files marked by `--stage-untracked` are skipped with `staged_untracked`
+pub fn check_git_tree(dir: &str, refuse: bool, staged_untracked: bool) -> Result<()> {
+    let output = crate::commands::CommandBuilder::default()
+        .args(["git", "-C", dir, "status", "--porcelain"])
+        .build()?
+        .exec_capture_split()?;
+    let files = match output {
+        Some(output) if output.status.success() => dirty_files(&output.stdout, staged_untracked),
+        Some(output) => {
+            debug!("Not checking the git tree: {}", output.stderr.trim());
+            return Ok(());
+        }
+        None => return Ok(()),
+    };
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    warn!(
+        "The git tree of the flake has uncommitted changes:\n{}",
+        files.join("\n")
+    );
+    if files.iter().any(|file| file.starts_with("??")) {
+        warn!("Untracked files are not seen by the flake, git add them to use them");
+    }
+
+    if refuse {
+        eyre::bail!(
+            "Refusing to switch to a dirty git tree, commit the changes or drop --refuse-dirty"
+        );
+    }
+    Ok(())
+}
+
+/// Adds the untracked files under the directory of a local flake with `--intent-to-add`, which
+/// makes them visible to the flake without staging their content
+pub fn stage_untracked(dir: &str, root: bool) -> Result<()> {
+    // ls-files only lists the files under the directory it runs in
+    let output = crate::commands::CommandBuilder::default()
+        .args([
+            "git",
+            "-C",
+            dir,
+            "ls-files",
+            "--others",
+            "--exclude-standard",
+            "-z",
+        ])
+        .build()?
+        .exec_capture_split()?;
+    let files: Vec<&str> = match &output {
+        Some(output) if output.status.success() => output
+            .stdout
+            .split('\0')
+            .filter(|file| !file.is_empty())
+            .collect(),
+        Some(output) => {
+            debug!("Not staging the untracked files: {}", output.stderr.trim());
+            return Ok(());
+        }
+        None => return Ok(()),
+    };
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    crate::commands::CommandBuilder::default()
+        .root(root)
+        .args(["git", "-C", dir, "add", "--intent-to-add", "--"])
+        .args(&files)
+        .message(format!("Staging the untracked files: {}", files.join(", ")))
+        .build()?
+        .exec()
+}
+
+/// Lines of `git status --porcelain`, one per changed file. The files added with
+/// `--intent-to-add` show up as ` A`, and are left out with `skip_intent_to_add`
+fn dirty_files(porcelain: &str, skip_intent_to_add: bool) -> Vec<String> {
+    porcelain
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter(|line| !(skip_intent_to_add && line.starts_with(" A ")))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Step running in the background while the next ones go on, like copying a closure while its
+/// diff renders. It's waited for when dropped, so that it never outlives the rebuild
+pub struct Task(Option<std::thread::JoinHandle<Result<()>>>);
+
+impl Task {
+    pub fn spawn(f: impl FnOnce() -> Result<()> + Send + 'static) -> Self {
+        Self(Some(std::thread::spawn(f)))
+    }
+
+    /// Waits for the step to finish, returning its error. Waiting again returns right away
+    pub fn wait(&mut self) -> Result<()> {
+        match self.0.take() {
+            Some(handle) => handle
+                .join()
+                .unwrap_or_else(|_| Err(eyre::eyre!("Background step panicked"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if let Err(err) = self.wait() {
+            debug!(?err, "Background step failed after an earlier error");
+        }
+    }
+}
+
+/// Maps the items on up to `jobs` threads, keeping them in order
+pub fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    jobs: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    if items.is_empty() {
+        return Vec::new();
+    }
+
+    let chunk_size = items.len().div_ceil(jobs);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(&f).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("Worker thread panicked"))
+            .collect()
+    })
+}
+
+/// Result of a rebuild, printed with --output-format json
+#[derive(Debug, Default, serde::Serialize)]
+pub struct RebuildSummary {
+    pub action: String,
+    /// Name of the configuration, the hostname for os and user@host for home
+    pub hostname: String,
+    pub flakeref: String,
+    pub store_path: Option<String>,
+    pub changed: bool,
+    pub reboot_required: bool,
+    pub elapsed_secs: f64,
+}
+
+impl RebuildSummary {
+    pub fn print(&mut self, start: std::time::Instant) -> Result<()> {
+        self.elapsed_secs = start.elapsed().as_secs_f64();
+        println!("{}", serde_json::to_string(self)?);
+        Ok(())
+    }
+}
+
+/// Asks the user to confirm, which is answered right away with --yes. Errors instead of waiting
+/// forever when there is no terminal to ask on
+pub fn confirm(prompt: &str) -> Result<bool> {
+    let args = GlobalArgs::get();
+    if args.yes {
+        debug!("Confirmed with --yes: {prompt}");
+        return Ok(true);
+    }
+
+    if !std::io::stdin().is_terminal() {
+        return Err(eyre::eyre!(
+            "Can't ask \"{prompt}\" without a terminal, pass --yes to confirm"
+        ));
+    }
+
+    info!("{prompt}");
+    Ok(dialoguer::Confirm::new()
+        .default(args.default_yes)
+        .interact()?)
+}
+
+/// Like [confirm], but also offers to show the diff again or the diff of the full closures
+/// before answering
+pub fn confirm_diff(
+    prompt: &str,
+    show_diff: impl Fn() -> Result<()>,
+    show_closure: impl Fn() -> Result<()>,
+) -> Result<bool> {
+    let args = GlobalArgs::get();
+    if args.yes || !std::io::stdin().is_terminal() {
+        return confirm(prompt);
+    }
+
+    let items = [
+        "Yes",
+        "No",
+        "Show the diff again",
+        "Show the full closure diff",
+    ];
+    loop {
+        info!("{prompt}");
+        let choice = dialoguer::Select::new()
+            .items(&items)
+            .default(if args.default_yes { 0 } else { 1 })
+            .interact()?;
+        match choice {
+            0 => return Ok(true),
+            1 => return Ok(false),
+            2 => show_diff()?,
+            _ => show_closure()?,
+        }
+    }
+}
+
+/// Expands the known provider names to their command line, other providers are taken as a raw
+/// command line
+fn diff_command(provider: &str) -> Vec<String> {
+    let expanded = match provider {
+        "nix" => "nix store diff-closures",
+        "nvd" => "nvd diff",
+        other => other,
+    };
+    expanded
+        .split_ascii_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn test_dirty_files() {
+    assert!(dirty_files("", false).is_empty());
+    assert_eq!(
+        dirty_files(" M hosts/laptop.nix\n?? modules/new.nix\n", false),
+        [" M hosts/laptop.nix", "?? modules/new.nix"]
+    );
+    assert_eq!(
+        dirty_files(" A modules/new.nix\n", false),
+        [" A modules/new.nix"]
+    );
+    assert_eq!(
+        dirty_files(" A modules/new.nix\n M hosts/laptop.nix\n", true),
+        [" M hosts/laptop.nix"]
+    );
+}
+
+#[test]
+fn test_stage_untracked_is_clean() {
+    let repo = tempfile::tempdir().unwrap();
+    let dir = repo.path().to_str().unwrap();
+    let git = |args: &[&str]| {
+        crate::commands::CommandBuilder::default()
+            .args([
+                "git",
+                "-C",
+                dir,
+                "-c",
+                "user.name=nh",
+                "-c",
+                "user.email=nh@localhost",
+            ])
+            .args(args)
+            .build()
+            .unwrap()
+            .exec_capture()
+            .unwrap()
+    };
+    git(&["init", "--quiet"]);
+    std::fs::write(repo.path().join("flake.nix"), "{}").unwrap();
+    git(&["add", "flake.nix"]);
+    git(&["commit", "--quiet", "-m", "init"]);
+    std::fs::write(repo.path().join("new.nix"), "{}").unwrap();
+
+    assert!(check_git_tree(dir, true, false).is_err());
+    stage_untracked(dir, false).unwrap();
+    assert!(check_git_tree(dir, true, false).is_err());
+    assert!(check_git_tree(dir, true, true).is_ok());
+}
+
+#[test]
+fn test_task() {
+    let mut ok = Task::spawn(|| Ok(()));
+    assert!(ok.wait().is_ok());
+
+    let mut failed = Task::spawn(|| Err(eyre::eyre!("copy failed")));
+    assert_eq!(failed.wait().unwrap_err().to_string(), "copy failed");
+    assert!(failed.wait().is_ok());
+}
+
+#[test]
+fn test_nvd_color() {
+    assert_eq!(nvd_color(ColorMode::Auto, false), None);
+    assert_eq!(nvd_color(ColorMode::Auto, true), Some("--color=never"));
+    assert_eq!(nvd_color(ColorMode::Always, true), Some("--color=always"));
+    assert_eq!(nvd_color(ColorMode::Never, false), Some("--color=never"));
+}
+
+#[test]
+fn test_diff_command() {
+    assert_eq!(diff_command("nix"), ["nix", "store", "diff-closures"]);
+    assert_eq!(diff_command("nvd"), ["nvd", "diff"]);
+    assert_eq!(diff_command("nvd diff"), ["nvd", "diff"]);
+    assert_eq!(diff_command("my-diff --short"), ["my-diff", "--short"]);
+}